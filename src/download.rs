@@ -0,0 +1,56 @@
+use log::info;
+use std::process::{Command, Stdio};
+
+/// Largest package accepted for download
+const MAX_PACKAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Errors downloading a package
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    /// Downloads go through the system `curl`, which is not installed
+    #[error("downloading packages requires curl, install it or download the package manually")]
+    CurlMissing,
+    #[error("failed to run curl: {0}")]
+    Curl(#[source] std::io::Error),
+    /// The server answered with an HTTP error, e.g. 404
+    #[error("downloading {url} failed: HTTP error response")]
+    Http { url: String },
+    #[error("downloading {url} failed: package exceeds {MAX_PACKAGE_SIZE} bytes")]
+    TooLarge { url: String },
+    /// Any other curl failure, e.g. DNS or TLS errors; `code` is `None` if curl was terminated
+    #[error("downloading {url} failed: {}", match .code { Some(code) => format!("curl exit code {}", code), None => "curl was terminated".to_string() })]
+    Failed { url: String, code: Option<i32> },
+}
+
+/// Whether a package path refers to an HTTP(S) URL
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Download a package into memory
+///
+/// Runs the system `curl`, which takes care of redirects, TLS and the progress bar on stderr, and
+/// blocks until it exits; without `curl` on the `PATH` this fails with [`DownloadError::CurlMissing`].
+pub fn fetch(url: &str) -> Result<Vec<u8>, DownloadError> {
+    info!("Downloading {} ...", url);
+    let output = Command::new("curl")
+        .args(["--fail", "--location", "--progress-bar", "--max-filesize"])
+        .arg(MAX_PACKAGE_SIZE.to_string())
+        .arg(url)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => DownloadError::CurlMissing,
+            _ => DownloadError::Curl(e),
+        })?;
+    if !output.status.success() {
+        let url = url.to_string();
+        return Err(match output.status.code() {
+            Some(22) => DownloadError::Http { url },
+            Some(63) => DownloadError::TooLarge { url },
+            code => DownloadError::Failed { url, code },
+        });
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,36 @@
+//! Top level error of a firmware update
+
+use crate::package::PackageError;
+use crate::protocol::ProtocolError;
+use crate::transport::TransportError;
+
+/// Everything that can go wrong during a firmware update
+#[derive(Debug, thiserror::Error)]
+pub enum DfuError {
+    /// The link to the target failed
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    /// The target rejected a request or responded unexpectedly
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// The package or init packet is invalid
+    #[error(transparent)]
+    Package(#[from] PackageError),
+    /// Reading the firmware failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The transfer was cancelled through [`DfuOptions::cancel`](crate::protocol::DfuOptions::cancel)
+    #[error("DFU cancelled")]
+    Cancelled,
+}
+
+impl DfuError {
+    /// Whether the link to the target failed rather than the target rejecting the update, so that
+    /// reconnecting and resuming the transfer may succeed
+    pub fn is_link_error(&self) -> bool {
+        matches!(
+            self,
+            DfuError::Transport(_) | DfuError::Protocol(ProtocolError::NoResponse { .. })
+        )
+    }
+}
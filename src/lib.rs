@@ -0,0 +1,16 @@
+//! Firmware updates for nRF devices running Nordic's BLE secure DFU bootloader
+
+pub mod download;
+pub mod error;
+pub mod package;
+pub mod protocol;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod transport;
+pub mod transport_btleplug;
+pub mod transport_serial;
+pub mod transport_tcp;
+#[cfg(target_os = "linux")]
+pub mod transport_usb;
+
+pub use error::DfuError;
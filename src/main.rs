@@ -1,26 +1,998 @@
-mod package;
-mod protocol;
-mod transport;
-mod transport_btleplug;
+use nrfdfu_ble::transport::{DfuTransport, TransportError};
+#[cfg(target_os = "linux")]
+use nrfdfu_ble::transport_usb;
+use nrfdfu_ble::{download, package, protocol, transport_btleplug, transport_serial, transport_tcp};
 
 use clap::Parser;
+use std::error::Error;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 /// Update firmware on nRF BLE DFU targets
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// BLE DFU target name
-    name: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Firmware update package path
-    pkg: String,
+    /// Log the control point requests and responses, twice to log the data writes too
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    #[command(flatten)]
+    update: UpdateArgs,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// DFU package utilities
+    #[command(subcommand)]
+    Pkg(PkgCommand),
+    /// List nearby devices without connecting to them
+    Scan {
+        /// Seconds to scan for
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        duration: u64,
+
+        /// List only devices advertising the DFU service, i.e. bootloaders and buttonless
+        /// applications
+        #[arg(long)]
+        dfu_only: bool,
+
+        /// Ignore devices with a weaker signal in dBm (or none reported)
+        #[arg(long, value_name = "DBM", allow_negative_numbers = true)]
+        min_rssi: Option<i16>,
+
+        /// Bluetooth adapter to use, by index or a part of its identifier (e.g. hci1), by default the
+        /// first
+        #[arg(long, value_name = "INDEX_OR_ID")]
+        adapter: Option<String>,
+
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum PkgCommand {
+    /// Print the contents of a DFU package without flashing it
+    Inspect {
+        /// Firmware update package path, extracted package directory, HTTP(S) URL, or "-" for stdin
+        pkg: String,
+
+        /// Print the image metadata as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a DFU package from a firmware binary and a (pre-signed) init packet
+    Create {
+        /// Output package path
+        output: String,
+
+        /// Init packet path
+        #[arg(long)]
+        init_pkt: String,
+
+        /// Firmware binary path
+        #[arg(long)]
+        fw_bin: String,
+
+        /// Image type: application, bootloader, softdevice, softdevice_bootloader or external_application
+        #[arg(long = "type", default_value = "application")]
+        kind: package::ImageKind,
+    },
+}
+
+/// Arguments that select a target on a serial port, USB or TCP
+#[cfg(target_os = "linux")]
+const WIRED: [&str; 4] = ["port", "usb", "usb_id", "tcp"];
+/// Arguments that select a target on a serial port or TCP, USB is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+const WIRED: [&str; 2] = ["port", "tcp"];
+
+#[derive(clap::Args)]
+// the BLE options do not apply to targets on a serial port, USB or TCP
+#[command(group(
+    clap::ArgGroup::new("wired")
+        .args(WIRED)
+        .multiple(true)
+        .conflicts_with_all([
+            "first", "name_ignore_case", "min_rssi", "addr", "id", "dfu_adv_name", "no_buttonless", "data_write_mode", "adapter", "connect_attempts",
+            "reconnect_attempts", "settle_delay_ms", "write_timeout_ms", "notify_timeout_ms", "scan_timeout", "app_name",
+        ])
+))]
+struct UpdateArgs {
+    /// BLE DFU target name, or a name prefix followed by `*`; omitted with --id, --port, --usb or --tcp
+    #[arg(required_unless_present_any = ["wired", "id"])]
+    name: Option<String>,
+
+    /// Serial port of a target running the serial (UART) bootloader, e.g. /dev/ttyACM0, instead of
+    /// a BLE target
+    #[arg(long, value_name = "PATH", conflicts_with = "tcp")]
+    port: Option<String>,
+
+    /// Flash a USB device running the open bootloader, e.g. an nRF52840 dongle, instead of a BLE
+    /// target
+    #[cfg(target_os = "linux")]
+    #[arg(long, conflicts_with_all = ["port", "tcp"])]
+    usb: bool,
+
+    /// USB vendor and product ID of the device to flash, implies --usb
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "VID:PID", conflicts_with_all = ["port", "tcp"])]
+    usb_id: Option<transport_usb::UsbId>,
+
+    /// Address of a simulated target to flash over TCP instead of a BLE target, e.g. 127.0.0.1:7777
+    #[arg(long, value_name = "ADDR")]
+    tcp: Option<String>,
+
+    /// Baud rate of the serial port
+    #[arg(long, default_value_t = 115200, requires = "port")]
+    baud: u32,
+
+    /// Flash the first device matching a name prefix instead of failing if several do
+    #[arg(long)]
+    first: bool,
+
+    /// Match names ignoring case and surrounding whitespace or NUL padding
+    #[arg(long)]
+    name_ignore_case: bool,
+
+    /// Ignore devices with a weaker signal in dBm (or none reported) and flash the strongest of
+    /// several matching, e.g. -60
+    #[arg(long, value_name = "DBM", allow_negative_numbers = true)]
+    min_rssi: Option<i16>,
+
+    /// Bluetooth address of the target, connected to without scanning if the adapter already knows
+    /// the device, and otherwise required of the device found by name where the platform exposes
+    /// addresses
+    #[arg(long, value_name = "ADDRESS")]
+    addr: Option<btleplug::api::BDAddr>,
+
+    /// Platform identifier of the target as the scan subcommand lists it, e.g. the CoreBluetooth
+    /// UUID on macOS, to find it by instead of a name
+    #[arg(long, value_name = "ID", conflicts_with_all = ["first", "min_rssi"])]
+    id: Option<String>,
+
+    /// Name the bootloader advertises after the switch from a buttonless application, at most 20
+    /// bytes, to tell it apart from identical units around
+    #[arg(long, value_name = "NAME", value_parser = parse_adv_name)]
+    dfu_adv_name: Option<String>,
+
+    /// Connect to the bootloader's DFU characteristics straight away, without triggering buttonless
+    /// DFU, for a target already in the bootloader whose application has an unrelated characteristic
+    /// with the buttonless UUID
+    #[arg(long, conflicts_with = "dfu_adv_name")]
+    no_buttonless: bool,
+
+    /// Firmware update package path, extracted package directory, HTTP(S) URL, or "-" for stdin;
+    /// several packages are flashed one after the other
+    #[arg(required_unless_present_any = ["init_pkt", "wired", "id"], conflicts_with = "init_pkt")]
+    pkg: Vec<String>,
+
+    /// Init packet path, used together with --fw-bin instead of a package
+    #[arg(long, requires = "fw_bin")]
+    init_pkt: Option<String>,
+
+    /// Firmware binary path, used together with --init-pkt instead of a package
+    #[arg(long, requires = "init_pkt")]
+    fw_bin: Option<String>,
+
+    /// Skip checking the firmware hash against the init packet before uploading
+    #[arg(long)]
+    skip_precheck: bool,
+
+    /// Skip checking the firmware size against the target's flash, e.g. for external flash bootloaders
+    #[arg(long)]
+    no_size_check: bool,
+
+    /// Skip checking the package's hardware and softdevice requirements against the target, and
+    /// flash even with --skip-current
+    #[arg(long)]
+    force: bool,
+
+    /// Skip an application image if the target already runs its version or a newer one
+    #[arg(long)]
+    skip_current: bool,
+
+    /// Verify the init packet signatures against this P-256 public key before uploading;
+    /// a PEM file, or the uncompressed key as hex string or file
+    #[arg(long, value_name = "PEM_OR_HEX")]
+    public_key: Option<String>,
+
+    /// Use this init packet instead of the one inside the package
+    #[arg(long, value_name = "PATH", requires = "pkg")]
+    init_pkt_override: Option<String>,
+
+    /// Refuse to flash unless the firmware image has this SHA-256 (hex)
+    #[arg(long, value_name = "HEX")]
+    expect_sha256: Option<String>,
+
+    /// Packet receipt notification interval in shards, 0 requests the CRC every --crc-interval shards
+    #[arg(long, default_value_t = 0)]
+    prn: u32,
+
+    /// Shards between CRC requests without receipt notifications, the CRC is always checked at the
+    /// end of each data object
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    crc_interval: u32,
+
+    /// Shards written ahead of the last packet receipt notification, at least --prn
+    #[arg(long, default_value_t = 0, requires = "prn")]
+    write_window: usize,
+
+    /// ATT MTU of the link, overriding what the target reports; e.g. 23 when the platform negotiates
+    /// less than the bootloader supports and longer writes get truncated
+    #[arg(long, value_parser = clap::value_parser!(u16).range(23..))]
+    mtu: Option<u16>,
+
+    /// Data writes with response (ack), without (noack), or without until writes keep getting lost
+    /// (auto)
+    #[arg(long, value_name = "MODE", default_value = "noack")]
+    data_write_mode: transport_btleplug::DataWriteMode,
+
+    /// Retries of a control point request that timed out
+    #[arg(long, default_value_t = 2)]
+    ctrl_retries: u32,
+
+    /// Delay before retrying a control point request, doubled with each further retry
+    #[arg(long, default_value_t = 100)]
+    retry_delay_ms: u64,
+
+    /// Timeout of control point responses and data writes, for very slow connection intervals;
+    /// creating and executing an object always get at least their defaults of 2.5 s and 10 s
+    #[arg(long, value_name = "MS")]
+    timeout_ms: Option<u64>,
+
+    /// Times the rest of a data object is re-sent after shards got lost
+    #[arg(long, default_value_t = 3)]
+    shard_retries: usize,
+
+    /// Times a data object is created and sent before giving up
+    #[arg(long, default_value_t = 3)]
+    object_attempts: usize,
+
+    /// Retries of executing an object that timed out or failed while the target was busy
+    #[arg(long, default_value_t = 2)]
+    execute_retries: u32,
+
+    /// Transfer everything from scratch, ignoring the init packet and firmware data the target holds
+    /// from an earlier transfer
+    #[arg(long)]
+    force_full: bool,
+
+    /// Skip querying the target's hardware and firmware versions, which also skips the size and
+    /// compatibility checks
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Times to reconnect and resume the transfer when the connection drops
+    #[arg(long, default_value_t = 3)]
+    reconnects: u32,
+
+    /// Attempts to connect to the target once found, with a growing delay in between
+    #[arg(long, default_value_t = 3)]
+    connect_attempts: u32,
+
+    /// Attempts of each reconnect, with a growing delay in between
+    #[arg(long, default_value_t = 3)]
+    reconnect_attempts: u32,
+
+    /// Bluetooth adapter to use, by index or a part of its identifier (e.g. hci1), by default the one
+    /// that finds the target
+    #[arg(long, value_name = "INDEX_OR_ID")]
+    adapter: Option<String>,
+
+    /// Milliseconds the bootloader gets to start advertising after the switch from a buttonless
+    /// application
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    settle_delay_ms: u64,
+
+    /// Milliseconds a write while connecting or disconnecting, or a control point write may take, e.g.
+    /// for targets with a long connection interval; a write timing out is a link failure, not retried
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    write_timeout_ms: u64,
+
+    /// Milliseconds to wait for a notification while connecting, i.e. the buttonless DFU response
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    notify_timeout_ms: u64,
+
+    /// Seconds to scan for the target before giving up
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    scan_timeout: u64,
+
+    /// Flash only the image of this type from a multi-image package
+    #[arg(long, value_name = "TYPE")]
+    only: Option<package::ImageKind>,
+
+    /// Print the summary of each uploaded image as a line of JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Exit once the last image is transferred, without waiting for the target to reboot into it
+    #[arg(long)]
+    no_confirm: bool,
+
+    /// Seconds to wait for the target to reset after an image, and for the application to advertise
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    reboot_timeout: u64,
+
+    /// Name the updated application advertises, by default the target name unless that is the
+    /// bootloader's
+    #[arg(long, value_name = "NAME")]
+    app_name: Option<String>,
+}
+
+/// Prints the messages of the library like the rest of the output, debug and trace records only
+/// of this crate as dependencies log plenty of their own
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= log::Level::Info || metadata.target().starts_with("nrfdfu_ble"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error => println!("Error: {}", record.args()),
+            log::Level::Warn => println!("Warning: {}", record.args()),
+            log::Level::Info => println!("{}", record.args()),
+            level => println!("[{}] {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn print_init_packet(init_pkt: &[u8], indent: &str) {
+    match package::InitPacket::parse(init_pkt) {
+        Ok(pkt) => {
+            for line in pkt.to_string().lines() {
+                println!("{}{}", indent, line);
+            }
+        }
+        Err(e) => println!("{}could not decode init packet: {}", indent, e),
+    }
+}
+
+/// Open a package given as file path, extracted package directory, URL or "-" for stdin
+fn open_package(path: &str) -> Result<Box<dyn package::ImageSource>, Box<dyn Error>> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        Ok(Box::new(package::from_bytes(bytes)?))
+    } else if download::is_url(path) {
+        Ok(Box::new(package::from_bytes(download::fetch(path)?)?))
+    } else if std::path::Path::new(path).is_dir() {
+        Ok(Box::new(package::Directory::new(std::path::Path::new(path))?))
+    } else {
+        Ok(Box::new(package::open(path)?))
+    }
+}
+
+fn inspect(path: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let mut source = open_package(path)?;
+    if json {
+        let metadata = package::PackageMetadata::from_source(&mut *source)?;
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+        return Ok(());
+    }
+    println!("{}", path);
+    for (index, (kind, files)) in source.images().into_iter().enumerate() {
+        let init_pkt = source.init_pkt(index)?;
+        let mut fw_pkt = Vec::new();
+        source.firmware(index)?.reader.read_to_end(&mut fw_pkt)?;
+        println!("  {:?}", kind);
+        println!(
+            "    init packet: {} ({} bytes, CRC32 {:#010x})",
+            files.dat_file,
+            init_pkt.len(),
+            crc32fast::hash(&init_pkt)
+        );
+        println!(
+            "    firmware:    {} ({} bytes, CRC32 {:#010x})",
+            files.bin_file,
+            fw_pkt.len(),
+            crc32fast::hash(&fw_pkt)
+        );
+        print_init_packet(&init_pkt, "    ");
+    }
+    Ok(())
+}
+
+fn create(output: &str, init_pkt: &str, fw_bin: &str, kind: package::ImageKind) -> Result<(), Box<dyn Error>> {
+    let file_name = |path: &str| {
+        std::path::Path::new(path)
+            .file_name()
+            .map_or(path.to_string(), |n| n.to_string_lossy().into_owned())
+    };
+    let image = package::ImagePair {
+        dat_file: file_name(init_pkt),
+        bin_file: file_name(fw_bin),
+        init_pkt: std::fs::read(init_pkt).map_err(|e| format!("{}: {}", init_pkt, e))?,
+        fw_pkt: std::fs::read(fw_bin).map_err(|e| format!("{}: {}", fw_bin, e))?,
+    };
+    if image.dat_file == image.bin_file {
+        return Err("init packet and firmware must have different file names".into());
+    }
+    let mut pkg = package::Package::default();
+    *pkg.image_mut(kind) = Some(image);
+    let file = std::fs::File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    package::write(file, &pkg)?;
+    println!("Created {}", output);
+    Ok(())
+}
+
+/// One image to flash, taken from one of the packages given on the command line
+struct Stage {
+    /// Index into the list of image sources
+    source: usize,
+    /// Index of the image within its source
+    index: usize,
+    kind: package::ImageKind,
+    files: package::ManifestImage,
+}
+
+async fn update(mut args: UpdateArgs) -> Result<(), Box<dyn Error>> {
+    #[cfg(target_os = "linux")]
+    let wired = args.port.is_some() || args.usb || args.usb_id.is_some() || args.tcp.is_some();
+    #[cfg(not(target_os = "linux"))]
+    let wired = args.port.is_some() || args.tcp.is_some();
+    if wired || args.id.is_some() {
+        // without a target name the first positional argument is a package
+        if let Some(pkg) = args.name.take() {
+            if args.init_pkt.is_some() {
+                return Err(format!("unexpected argument {} with --init-pkt", pkg).into());
+            }
+            args.pkg.insert(0, pkg);
+        }
+        if args.pkg.is_empty() && args.init_pkt.is_none() {
+            return Err("no package given".into());
+        }
+    }
+    let mut labels = Vec::new();
+    let mut sources: Vec<Box<dyn package::ImageSource>> = Vec::new();
+    match (args.pkg.as_slice(), &args.init_pkt, &args.fw_bin) {
+        ([], Some(init_pkt), Some(fw_bin)) => {
+            labels.push(fw_bin.clone());
+            sources.push(Box::new(package::from_files(init_pkt, fw_bin)?));
+        }
+        (pkgs, _, _) => {
+            for pkg in pkgs {
+                labels.push(
+                    std::path::Path::new(pkg)
+                        .file_name()
+                        .map_or(pkg.clone(), |n| n.to_string_lossy().into_owned()),
+                );
+                sources.push(open_package(pkg).map_err(|e| format!("{}: {}", pkg, e))?);
+            }
+        }
+    }
+    let name = match (&args.name, &args.id) {
+        (Some(name), _) => name.as_str(),
+        (None, Some(id)) => id.as_str(),
+        (None, None) => transport_btleplug::BOOTLOADER_NAME,
+    };
+
+    let mut images: Vec<Stage> = Vec::new();
+    for (source_index, source) in sources.iter().enumerate() {
+        for (index, (kind, files)) in source.images().into_iter().enumerate() {
+            images.push(Stage {
+                source: source_index,
+                index,
+                kind,
+                files,
+            });
+        }
+    }
+    if let Some(only) = args.only {
+        if !images.iter().any(|image| image.kind == only) {
+            let kinds: Vec<_> = images.iter().map(|image| image.kind.manifest_key()).collect();
+            return Err(format!(
+                "package contains no {} image, available: {}",
+                only.manifest_key(),
+                kinds.join(", ")
+            )
+            .into());
+        }
+        images.retain(|image| image.kind == only);
+    }
+    let stages = images.len();
+
+    if let Some(path) = &args.init_pkt_override {
+        let image = match images.as_mut_slice() {
+            [image] => image,
+            _ => return Err("--init-pkt-override needs a single image package, select one with --only".into()),
+        };
+        log::warn!(
+            "ignoring {} from the package, using init packet {} for the {:?} image",
+            image.files.dat_file,
+            path,
+            image.kind
+        );
+        image.files.dat_file = path.clone();
+        let inner = sources.remove(image.source);
+        sources.insert(
+            image.source,
+            Box::new(package::InitPktOverride {
+                source: inner,
+                index: image.index,
+                dat_file: path.clone(),
+                init_pkt: std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?,
+            }),
+        );
+    }
+
+    if !args.skip_precheck {
+        for image in &images {
+            let source = &mut sources[image.source];
+            let init_pkt = source.init_pkt(image.index)?;
+            let fw = source.firmware(image.index)?;
+            package::InitPacket::parse(&init_pkt)
+                .and_then(|init| init.verify_firmware(fw.reader))
+                .map_err(|e| format!("{}: {} (use --skip-precheck to upload anyway)", image.files.bin_file, e))?;
+        }
+    }
+
+    if let Some(key) = &args.public_key {
+        let key = match std::fs::read_to_string(key) {
+            Ok(text) => package::parse_public_key(&text),
+            Err(_) => package::parse_public_key(key),
+        }
+        .map_err(|e| format!("--public-key: {}", e))?;
+        for image in &images {
+            package::InitPacket::parse(&sources[image.source].init_pkt(image.index)?)
+                .and_then(|init| init.verify_signature(&key))
+                .map_err(|e| format!("{:?} image ({}): {}", image.kind, image.files.dat_file, e))?;
+        }
+        println!("Init packet signatures verified");
+    }
+
+    let mut digests = Vec::new();
+    for image in &images {
+        let mut fw = package::Sha256Reader::new(sources[image.source].firmware(image.index)?.reader);
+        std::io::copy(&mut fw, &mut std::io::sink())?;
+        digests.push(fw.hex_digest());
+    }
+    if let Some(expected) = &args.expect_sha256 {
+        let (digest, image) = match (digests.as_slice(), images.as_slice()) {
+            ([digest], [image]) => (digest, image),
+            _ => return Err("--expect-sha256 needs a single image package, select one with --only".into()),
+        };
+        let expected = expected.trim().to_ascii_lowercase();
+        if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("--expect-sha256: expected 64 hex digits".into());
+        }
+        if *digest != expected {
+            return Err(format!(
+                "{}: SHA-256 mismatch: expected {}, firmware is {}",
+                image.files.bin_file, expected, digest
+            )
+            .into());
+        }
+    }
+
+    let mut options = protocol::DfuOptions {
+        preflight: !args.no_preflight,
+        check_size: !args.no_size_check,
+        check_compat: !args.force,
+        prn: args.prn,
+        crc_interval: args.crc_interval as usize,
+        write_window: args.write_window,
+        retry: protocol::RetryPolicy {
+            retries: args.ctrl_retries,
+            delay: std::time::Duration::from_millis(args.retry_delay_ms),
+            ..Default::default()
+        },
+        timeouts: match args.timeout_ms {
+            Some(ms) => {
+                let timeout = std::time::Duration::from_millis(ms);
+                let default = protocol::Timeouts::default();
+                protocol::Timeouts {
+                    request: timeout,
+                    crc: timeout,
+                    create: default.create.max(timeout),
+                    execute: default.execute.max(timeout),
+                    write: timeout,
+                }
+            }
+            None => Default::default(),
+        },
+        shard_retries: args.shard_retries,
+        object_attempts: args.object_attempts,
+        execute_retries: args.execute_retries,
+        force_full: args.force_full,
+        skip_current: args.skip_current && !args.force,
+        mtu: args.mtu.map(usize::from),
+        ..Default::default()
+    };
+
+    // bytes of the current stage the target confirmed, for the error after a lost connection
+    let confirmed = Arc::new(AtomicUsize::new(0));
+    options.confirmed = Some(confirmed.clone());
+
+    // once Ctrl-C is being listened for it no longer terminates the process, so it has to cover
+    // everything from connecting to the last stage
+    let reboot_timeout = std::time::Duration::from_secs(args.reboot_timeout);
+    let app_name = match &args.app_name {
+        _ if wired => None,
+        Some(app_name) => Some(app_name.as_str()),
+        // the identifier is no name to look for
+        None if args.id.is_some() => None,
+        None => Some(name).filter(|name| *name != transport_btleplug::BOOTLOADER_NAME),
+    };
+    let connect = transport_btleplug::ConnectOptions {
+        adapter: args.adapter.clone(),
+        first: args.first,
+        ignore_case: args.name_ignore_case,
+        min_rssi: args.min_rssi,
+        address: args.addr,
+        id: args.id.clone(),
+        dfu_adv_name: args.dfu_adv_name.clone(),
+        no_buttonless: args.no_buttonless,
+        mtu: args.mtu.map(usize::from),
+        data_write_mode: args.data_write_mode,
+        scan_timeout: std::time::Duration::from_secs(args.scan_timeout),
+        settle_delay: std::time::Duration::from_millis(args.settle_delay_ms),
+        connect_attempts: args.connect_attempts,
+        reconnect_attempts: args.reconnect_attempts,
+        write_timeout: std::time::Duration::from_millis(args.write_timeout_ms),
+        notify_timeout: std::time::Duration::from_millis(args.notify_timeout_ms),
+        ..Default::default()
+    };
+    let serial = transport_serial::SerialOptions {
+        baud: args.baud,
+        reconnect_timeout: reboot_timeout,
+        ..Default::default()
+    };
+    let mut connection = None;
+    // whether to tell the target to abort after a failure, not while the link is gone or once an image
+    // is complete and being activated
+    let abort = std::cell::Cell::new(true);
+    let res = {
+        let flash = async {
+            let transport = connection.insert(match (&args.port, &args.tcp) {
+                (Some(port), _) => Link::Serial(transport_serial::DfuTransportSerial::open(port, &serial).await?),
+                (_, Some(addr)) => Link::Tcp(transport_tcp::DfuTransportTcp::connect(addr).await?),
+                #[cfg(target_os = "linux")]
+                _ if wired => {
+                    Link::Usb(transport_usb::DfuTransportUsb::open(args.usb_id.unwrap_or_default(), &serial).await?)
+                }
+                _ => Link::Ble(transport_btleplug::DfuTransportBtleplug::connect(name, &connect).await?),
+            });
+            // whether the target resets after the previous stage, a skipped one leaves it in the bootloader
+            let mut rebooting = false;
+            for (stage, image) in images.iter().enumerate() {
+                let label = &labels[image.source];
+                if rebooting {
+                    abort.set(false);
+                    // the target resets after activating the previous image and comes back in DFU mode
+                    let done = &images[stage - 1];
+                    let completed = format!(
+                        "stage {}/{} ({:?} image from {}) completed",
+                        stage, stages, done.kind, labels[done.source]
+                    );
+                    match transport {
+                        Link::Ble(transport) => {
+                            transport
+                                .wait_disconnect(reboot_timeout)
+                                .await
+                                .map_err(|e| format!("{}, but {}", completed, e))?;
+                            transport.close().await;
+                            // the bootloader comes back where it was, not at the application's address
+                            let options = transport.options().clone();
+                            *transport = transport_btleplug::DfuTransportBtleplug::connect(
+                                transport_btleplug::BOOTLOADER_NAME,
+                                &options,
+                            )
+                            .await
+                            .map_err(|e| format!("{}, but reconnecting to the bootloader failed: {}", completed, e))?;
+                        }
+                        // the port stays, the bootloader answers on it again once it is back
+                        _ => transport
+                            .reconnect()
+                            .await
+                            .map_err(|e| format!("{}, but reconnecting to the bootloader failed: {}", completed, e))?,
+                    }
+                    abort.set(true);
+                }
+                let source = &mut sources[image.source];
+                let init_pkt = source.init_pkt(image.index)?;
+                let fw_len = source.firmware(image.index)?.len;
+                println!(
+                    "Stage {}/{} [{}]: uploading {:?} image ({} bytes, SHA-256 {})",
+                    stage + 1,
+                    stages,
+                    label,
+                    image.kind,
+                    fw_len,
+                    digests[stage]
+                );
+                print_init_packet(&init_pkt, "  ");
+                confirmed.store(0, Ordering::Relaxed);
+                let mut reconnects = 0;
+                let (summary, reader) = loop {
+                    // the bootloader keeps the data of a dropped connection, the transfer resumes from
+                    // there but the firmware is read from the start again
+                    let fw = source.firmware(image.index)?;
+                    let mut reader = package::Sha256Reader::new(fw.reader);
+                    // --force-full discards what an earlier run left, not what this one sent
+                    options.force_full = args.force_full && reconnects == 0;
+                    let res =
+                        protocol::dfu_run_with_options(&*transport, &init_pkt, &mut reader, fw.len, &options).await;
+                    let e = match res {
+                        Ok(summary) => break (summary, reader),
+                        Err(e) => e,
+                    };
+                    let failed = format!(
+                        "stage {}/{} [{}] failed after {} of {} bytes were confirmed: {}",
+                        stage + 1,
+                        stages,
+                        label,
+                        confirmed.load(Ordering::Relaxed),
+                        fw_len,
+                        e
+                    );
+                    // the run aborts itself unless the link failed
+                    abort.set(false);
+                    if !e.is_link_error() || reconnects == args.reconnects {
+                        return Err(failed.into());
+                    }
+                    reconnects += 1;
+                    println!("Warning: {}, reconnecting ({}/{})", failed, reconnects, args.reconnects);
+                    transport
+                        .reconnect()
+                        .await
+                        .map_err(|e| format!("{}, reconnecting failed: {}", failed, e))?;
+                    abort.set(true);
+                };
+                rebooting = !summary.skipped;
+                if summary.skipped {
+                    println!(
+                        "Stage {}/{} [{}]: skipped {:?} image, the target already runs its version",
+                        stage + 1,
+                        stages,
+                        label,
+                        image.kind
+                    );
+                } else {
+                    println!(
+                        "Stage {}/{} [{}]: uploaded {:?} image ({} bytes, SHA-256 {})",
+                        stage + 1,
+                        stages,
+                        label,
+                        image.kind,
+                        fw_len,
+                        reader.hex_digest()
+                    );
+                }
+                if args.json {
+                    println!("{}", serde_json::to_string(&summary)?);
+                } else {
+                    println!("  {}", summary);
+                }
+            }
+            if !rebooting {
+                // aborting makes the bootloader reset into the application it was left in for
+                shutdown(transport).await;
+            } else if !args.no_confirm {
+                abort.set(false);
+                match transport {
+                    Link::Ble(transport) => confirm_activation(transport, app_name, &connect, reboot_timeout).await?,
+                    _ => {
+                        println!("Transfer complete, activation is only confirmed over BLE")
+                    }
+                }
+            }
+            Ok::<(), Box<dyn Error>>(())
+        };
+        tokio::select! {
+            res = flash => res,
+            _ = tokio::signal::ctrl_c() => Err("interrupted".into()),
+        }
+    };
+    if let (Err(_), Some(transport), true) = (&res, &connection, abort.get()) {
+        shutdown(transport).await;
+    }
+    // also after confirming the activation, which leaves the scan and subscription behind
+    if let Some(Link::Ble(transport)) = &connection {
+        transport.close().await;
+    }
+    res
+}
+
+/// Wait for the target to reset after the last image and, if its name is known, for the
+/// application to advertise, as the bootloader only validates and activates the image then
+async fn confirm_activation(
+    transport: &transport_btleplug::DfuTransportBtleplug,
+    app_name: Option<&str>,
+    connect: &transport_btleplug::ConnectOptions,
+    within: std::time::Duration,
+) -> Result<(), Box<dyn Error>> {
+    let unconfirmed = "transfer complete, activation unconfirmed";
+    transport
+        .wait_disconnect(within)
+        .await
+        .map_err(|e| format!("{}: {} (use --no-confirm to skip this check)", unconfirmed, e))?;
+    let app_name = match app_name {
+        Some(app_name) => app_name,
+        None => {
+            println!("Update activated: target rebooted");
+            return Ok(());
+        }
+    };
+    match transport_btleplug::scan_for(&[app_name, transport_btleplug::BOOTLOADER_NAME], connect, within).await {
+        Ok(Some(found)) if connect.name_matches(app_name, &found) => {
+            println!("Update activated: target rebooted and advertises as {}", found);
+            Ok(())
+        }
+        Ok(Some(found)) => Err(format!(
+            "transfer complete, but the target rebooted into the bootloader ({}), the image was not activated",
+            found
+        )
+        .into()),
+        Ok(None) => Err(format!(
+            "{}: target rebooted, but {} did not advertise within {} s (set the application name with --app-name)",
+            unconfirmed,
+            app_name,
+            within.as_secs()
+        )
+        .into()),
+        Err(e) => Err(format!("{}: target rebooted, but scanning failed: {}", unconfirmed, e).into()),
+    }
+}
+
+async fn scan(
+    options: &transport_btleplug::ConnectOptions,
+    within: std::time::Duration,
+    dfu_only: bool,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let found = transport_btleplug::scan(options, within, dfu_only).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+        return Ok(());
+    }
+    println!("{:<24} {:<40} {:>5} DFU", "NAME", "ID", "RSSI");
+    for device in &found {
+        println!(
+            "{:<24} {:<40} {:>5} {}",
+            device.name.as_deref().unwrap_or("-"),
+            device.id,
+            device.rssi.map_or("-".to_string(), |rssi| rssi.to_string()),
+            if device.dfu { "yes" } else { "no" }
+        );
+    }
+    println!("{} devices found", found.len());
+    Ok(())
+}
+
+fn parse_adv_name(name: &str) -> Result<String, String> {
+    transport_btleplug::check_adv_name(name)?;
+    Ok(name.to_string())
+}
+
+/// Link to the target, over BLE, a serial port, USB or TCP
+// a single instance, boxing the BLE transport would save nothing
+#[allow(clippy::large_enum_variant)]
+enum Link {
+    Ble(transport_btleplug::DfuTransportBtleplug),
+    Serial(transport_serial::DfuTransportSerial),
+    #[cfg(target_os = "linux")]
+    Usb(transport_usb::DfuTransportUsb),
+    Tcp(transport_tcp::DfuTransportTcp),
+}
+
+impl Link {
+    async fn reconnect(&mut self) -> Result<(), TransportError> {
+        match self {
+            Link::Ble(transport) => transport.reconnect().await,
+            Link::Serial(transport) => transport.reconnect().await,
+            #[cfg(target_os = "linux")]
+            Link::Usb(transport) => transport.reconnect().await,
+            Link::Tcp(transport) => transport.reconnect().await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DfuTransport for Link {
+    async fn mtu(&self) -> Option<usize> {
+        match self {
+            Link::Ble(transport) => transport.mtu().await,
+            Link::Serial(transport) => transport.mtu().await,
+            #[cfg(target_os = "linux")]
+            Link::Usb(transport) => transport.mtu().await,
+            Link::Tcp(transport) => transport.mtu().await,
+        }
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: std::time::Duration) -> Result<(), TransportError> {
+        match self {
+            Link::Ble(transport) => transport.write_data(bytes, timeout).await,
+            Link::Serial(transport) => transport.write_data(bytes, timeout).await,
+            #[cfg(target_os = "linux")]
+            Link::Usb(transport) => transport.write_data(bytes, timeout).await,
+            Link::Tcp(transport) => transport.write_data(bytes, timeout).await,
+        }
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: std::time::Duration) -> Result<Vec<u8>, TransportError> {
+        match self {
+            Link::Ble(transport) => transport.request_ctrl(bytes, timeout).await,
+            Link::Serial(transport) => transport.request_ctrl(bytes, timeout).await,
+            #[cfg(target_os = "linux")]
+            Link::Usb(transport) => transport.request_ctrl(bytes, timeout).await,
+            Link::Tcp(transport) => transport.request_ctrl(bytes, timeout).await,
+        }
+    }
+    async fn receive_ctrl(&self, timeout: std::time::Duration) -> Result<Vec<u8>, TransportError> {
+        match self {
+            Link::Ble(transport) => transport.receive_ctrl(timeout).await,
+            Link::Serial(transport) => transport.receive_ctrl(timeout).await,
+            #[cfg(target_os = "linux")]
+            Link::Usb(transport) => transport.receive_ctrl(timeout).await,
+            Link::Tcp(transport) => transport.receive_ctrl(timeout).await,
+        }
+    }
+}
+
+/// Abort the transfer and disconnect on a best effort basis, bounded in case the link is already dead
+async fn shutdown(transport: &Link) {
+    match tokio::time::timeout(std::time::Duration::from_secs(2), protocol::abort(transport)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::debug!("aborting the transfer failed: {}", e),
+        Err(_) => log::debug!("aborting the transfer timed out"),
+    }
+    if let Link::Ble(transport) = transport {
+        transport.close().await;
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> std::process::ExitCode {
     let args = Args::parse();
-    let (init_pkt, fw_pkt) = package::extract(&args.pkg)?;
-    let transport = &transport_btleplug::DfuTransportBtleplug::new(&args.name).await?;
-
-    protocol::dfu_run(&transport, &init_pkt, &fw_pkt).await
+    static LOGGER: Logger = Logger;
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(match args.verbose {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+    let res = match args.command {
+        Some(Command::Pkg(PkgCommand::Inspect { pkg, json })) => inspect(&pkg, json),
+        Some(Command::Pkg(PkgCommand::Create {
+            output,
+            init_pkt,
+            fw_bin,
+            kind,
+        })) => create(&output, &init_pkt, &fw_bin, kind),
+        Some(Command::Scan {
+            duration,
+            dfu_only,
+            min_rssi,
+            adapter,
+            json,
+        }) => {
+            let options = transport_btleplug::ConnectOptions {
+                adapter,
+                min_rssi,
+                ..Default::default()
+            };
+            scan(&options, std::time::Duration::from_secs(duration), dfu_only, json).await
+        }
+        None => update(args.update).await,
+    };
+    match res {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::ExitCode::FAILURE
+        }
+    }
 }
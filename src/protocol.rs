@@ -1,22 +1,31 @@
-use crate::transport::DfuTransport;
+use crate::error::DfuError;
+use crate::package::{to_hex, FwType, InitPacket};
+use crate::transport::{DfuTransport, TransportError};
 
-use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::error::Error;
+use log::{debug, info, trace, warn};
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
+use serde::Serialize;
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 // As defined in nRF5_SDK_17.1.0_ddde560/components/libraries/bootloader/dfu/nrf_dfu_req_handler.h
 
 /// DFU Object variants
 #[derive(Debug, Copy, Clone, IntoPrimitive)]
 #[repr(u8)]
-enum Object {
+pub enum Object {
     Command = 0x01,
     Data = 0x02,
 }
 
 /// DFU Command opcodes
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
-enum OpCode {
+#[non_exhaustive]
+pub enum OpCode {
     ProtocolVersion = 0x00,
     ObjectCreate = 0x01,
     ReceiptNotifSet = 0x02,
@@ -32,9 +41,10 @@ enum OpCode {
 }
 
 /// DFU Response codes
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
-enum ResponseCode {
+#[non_exhaustive]
+pub enum ResponseCode {
     Invalid = 0x00,
     Success = 0x01,
     OpCodeNotSupported = 0x02,
@@ -47,145 +57,2194 @@ enum ResponseCode {
     ExtError = 0x0B,
 }
 
+impl std::fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Extended error codes following an `ExtError` response, see `nrf_dfu_ext_error_code_t`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, FromPrimitive)]
+#[repr(u8)]
+pub enum ExtError {
+    NoError = 0x00,
+    InvalidErrorCode = 0x01,
+    WrongCommandFormat = 0x02,
+    UnknownCommand = 0x03,
+    InitCommandInvalid = 0x04,
+    FwVersionFailure = 0x05,
+    HwVersionFailure = 0x06,
+    SdVersionFailure = 0x07,
+    SignatureMissing = 0x08,
+    WrongHashType = 0x09,
+    HashFailed = 0x0A,
+    WrongSignatureType = 0x0B,
+    VerificationFailed = 0x0C,
+    InsufficientSpace = 0x0D,
+    #[num_enum(catch_all)]
+    Unknown(u8),
+}
+
+impl ExtError {
+    /// Name of the code in the Nordic SDK, without the `NRF_DFU_EXT_ERROR_` prefix
+    fn name(&self) -> &'static str {
+        match self {
+            ExtError::NoError => "NO_ERROR",
+            ExtError::InvalidErrorCode => "INVALID_ERROR_CODE",
+            ExtError::WrongCommandFormat => "WRONG_COMMAND_FORMAT",
+            ExtError::UnknownCommand => "UNKNOWN_COMMAND",
+            ExtError::InitCommandInvalid => "INIT_COMMAND_INVALID",
+            ExtError::FwVersionFailure => "FW_VERSION_FAILURE",
+            ExtError::HwVersionFailure => "HW_VERSION_FAILURE",
+            ExtError::SdVersionFailure => "SD_VERSION_FAILURE",
+            ExtError::SignatureMissing => "SIGNATURE_MISSING",
+            ExtError::WrongHashType => "WRONG_HASH_TYPE",
+            ExtError::HashFailed => "HASH_FAILED",
+            ExtError::WrongSignatureType => "WRONG_SIGNATURE_TYPE",
+            ExtError::VerificationFailed => "VERIFICATION_FAILED",
+            ExtError::InsufficientSpace => "INSUFFICIENT_SPACE",
+            ExtError::Unknown(_) => "UNKNOWN",
+        }
+    }
+
+    fn hint(&self) -> &'static str {
+        match self {
+            ExtError::NoError => "bootloader reported an extended error without setting its code",
+            ExtError::InvalidErrorCode => "bootloader reported an invalid extended error code",
+            ExtError::WrongCommandFormat => "request was malformed",
+            ExtError::UnknownCommand => "request is not supported by the bootloader",
+            ExtError::InitCommandInvalid => "init packet has an invalid update type or lacks required fields",
+            ExtError::FwVersionFailure => "package version is not newer than installed version",
+            ExtError::HwVersionFailure => "package is built for a different hardware version",
+            ExtError::SdVersionFailure => "package does not support the installed softdevice",
+            ExtError::SignatureMissing => "init packet is not signed, but the bootloader requires a signature",
+            ExtError::WrongHashType => "init packet uses a hash type the bootloader does not support",
+            ExtError::HashFailed => "bootloader could not calculate the firmware hash",
+            ExtError::WrongSignatureType => "init packet uses a signature type the bootloader does not support",
+            ExtError::VerificationFailed => {
+                "signature does not match the bootloader's key, or the firmware does not match the init packet hash"
+            }
+            ExtError::InsufficientSpace => "firmware does not fit into the available flash",
+            ExtError::Unknown(_) => "extended error code not defined by the Nordic SDK",
+        }
+    }
+}
+
+impl std::fmt::Display for ExtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtError::Unknown(code) => write!(f, "{} ({:#04x}): {}", self.name(), code, self.hint()),
+            _ => write!(f, "{}: {}", self.name(), self.hint()),
+        }
+    }
+}
+
+/// Errors in the exchange with the bootloader
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    /// The bootloader rejected a request
+    #[error("{opcode:?} request failed: {code}")]
+    Rejected { opcode: OpCode, code: ResponseCode },
+    /// The bootloader rejected a request with an extended error, e.g. an init packet it does not accept
+    #[error("{opcode:?} request failed: {code}")]
+    Extended { opcode: OpCode, code: ExtError },
+    /// The bootloader rejected the init packet or the transferred image, with what the package and the
+    /// target report on the failed check
+    #[error("{opcode:?} request failed: {code}; {details}")]
+    ValidationFailed {
+        opcode: OpCode,
+        code: ExtError,
+        details: String,
+    },
+    /// A response that does not follow the protocol
+    #[error("invalid response to {opcode:?} request: {reason}")]
+    InvalidResponse { opcode: OpCode, reason: &'static str },
+    /// A response to a different request than the one sent
+    #[error("response to opcode {received:#04x} instead of the {expected:?} request")]
+    UnexpectedOpcode { expected: OpCode, received: u8 },
+    /// The response is shorter or longer than its opcode and result call for
+    #[error("malformed response to {opcode:?} request: {}", to_hex(.response))]
+    MalformedResponse { opcode: OpCode, response: Vec<u8> },
+    /// Every attempt of a request timed out, see [`RetryPolicy`]
+    #[error("No response to {opcode:?} request after {attempts} attempts")]
+    NoResponse { opcode: OpCode, attempts: u32 },
+    /// The control point does not answer a ping
+    #[error("DFU control point unresponsive: {0}")]
+    Unresponsive(String),
+    #[error(
+        "target uses DFU protocol version {version} (response {response:02x?}), \
+         only version {PROTOCOL_VERSION} is supported"
+    )]
+    UnsupportedVersion { version: u8, response: Vec<u8> },
+    /// The package does not suit the target, see [`DfuOptions::check_size`] and [`DfuOptions::check_compat`]
+    #[error("{0}")]
+    Incompatible(String),
+    /// The target holds a different amount of data than was sent
+    #[error("Length mismatch: target has {actual} bytes, expected {expected}")]
+    LengthMismatch { expected: usize, actual: usize },
+    /// The data the target holds is corrupted
+    #[error("CRC mismatch at {offset} bytes")]
+    CrcMismatch { offset: usize },
+    /// A data object stayed corrupted after re-sending it
+    #[error("CRC mismatch: data object at {offset} bytes failed to transfer after {attempts} attempts")]
+    TransferFailed { offset: usize, attempts: usize },
+    /// The target holds more data of an earlier transfer than the firmware has
+    #[error("target reports {resume_offset} bytes already transferred, firmware has only {fw_len}")]
+    ResumeBeyondEnd { resume_offset: usize, fw_len: usize },
+    /// The firmware reader did not provide the announced number of bytes
+    #[error("firmware length mismatch: expected {expected} bytes, got {actual}")]
+    FirmwareLength { expected: usize, actual: usize },
+    /// After the last data object the target does not hold exactly the firmware
+    #[error(
+        "final image check failed: target has {actual_len} bytes with CRC {actual_crc:#010x}, \
+         expected {expected_len} bytes with CRC {expected_crc:#010x}"
+    )]
+    ImageMismatch {
+        expected_len: usize,
+        expected_crc: u32,
+        actual_len: usize,
+        actual_crc: u32,
+    },
+}
+
+/// DFU protocol version implemented here
+const PROTOCOL_VERSION: u8 = 1;
+
+/// ATT write command header (opcode and handle) in each data point shard
+const ATT_HEADER_SIZE: usize = 3;
+/// ATT MTU every link supports, used when neither the link nor the target reports one
+const DEFAULT_ATT_MTU: usize = 23;
+/// Shard size that fits the default ATT MTU
+const MIN_SHARD_SIZE: usize = DEFAULT_ATT_MTU - ATT_HEADER_SIZE;
+
+/// Size of the Master Boot Record at the start of flash
+const MBR_SIZE: usize = 0x1000;
+/// Smallest data object size tried when the target lacks the resources for larger ones, one flash
+/// page of nRF52 devices unless the target reports its page size
+const MIN_OBJECT_SIZE: usize = 0x1000;
+
+/// Target hardware as reported by the HardwareVersion request
+#[derive(Debug, Clone, Serialize)]
+pub struct HardwareVersion {
+    pub part: u32,
+    pub variant: u32,
+    pub rom_size: u32,
+    pub ram_size: u32,
+    pub rom_page_size: u32,
+}
+
+impl std::fmt::Display for HardwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "nRF{:x} variant {:08x}: {} bytes flash ({} byte pages), {} bytes RAM",
+            self.part, self.variant, self.rom_size, self.rom_page_size, self.ram_size
+        )
+    }
+}
+
+/// Firmware types reported by the FirmwareVersion request
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize)]
+#[repr(u8)]
+pub enum FirmwareType {
+    Softdevice = 0x00,
+    Application = 0x01,
+    Bootloader = 0x02,
+    Unknown = 0xFF,
+}
+
+/// Firmware installed on the target as reported by the FirmwareVersion request
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareVersion {
+    pub fw_type: FirmwareType,
+    /// For the softdevice in the format major * 1000000 + minor * 1000 + bugfix
+    pub version: u32,
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} version {} at {:#x} ({} bytes)",
+            self.fw_type, self.version, self.addr, self.len
+        )
+    }
+}
+
+/// Softdevice firmware IDs (as used in `sd_req`) by softdevice version, from `nrfutil pkg generate --help`
+///
+/// The FirmwareVersion request only reports the version, so e.g. S132 and S140 of the
+/// same version cannot be told apart.
+const SOFTDEVICE_FWIDS: &[(u32, &[u32])] = &[
+    (5000000, &[0x9D]),
+    (5001000, &[0xA5]),
+    (6000000, &[0xA7, 0xA8, 0xA9]),
+    (6001000, &[0xAE, 0xAF, 0xB0]),
+    (6001001, &[0xB6, 0xB7, 0xB8]),
+    (7000000, &[0xC1, 0xC2, 0xC3, 0xC4]),
+    (7000001, &[0xCA, 0xCB, 0xCC, 0xCD]),
+    (7002000, &[0x0100, 0x0101, 0x0102, 0x0103]),
+];
+
+/// `sd_req` entry accepted regardless of the installed softdevice (debug packages)
+const SD_REQ_ANY: u32 = 0xFFFE;
+
+/// Length of the payload following the result of a successful response to `opcode`
+fn response_len(opcode: OpCode) -> usize {
+    match opcode {
+        OpCode::ProtocolVersion | OpCode::Ping => 1,
+        OpCode::MtuGet => 2,
+        OpCode::CrcGet => 8,
+        OpCode::ObjectSelect => 12,
+        OpCode::FirmwareVersion => 13,
+        OpCode::HardwareVersion => 20,
+        OpCode::ObjectCreate
+        | OpCode::ReceiptNotifSet
+        | OpCode::ObjectExecute
+        | OpCode::ObjectWrite
+        | OpCode::Abort => 0,
+    }
+}
+
+/// A control point response whose header, result and length were validated, see [`Response::parse`]
+#[derive(Debug, Clone, Copy)]
+pub struct Response<'b> {
+    opcode: OpCode,
+    bytes: &'b [u8],
+}
+
+impl<'b> Response<'b> {
+    /// Validate the response `bytes` to an `opcode` request.
+    ///
+    /// Responses with a result other than success become the matching error, and the length of
+    /// a successful response must fit its opcode, so decoders can rely on the payload being complete.
+    pub fn parse(opcode: OpCode, bytes: &'b [u8]) -> Result<Self, ProtocolError> {
+        let invalid = |reason| ProtocolError::InvalidResponse { opcode, reason };
+        let malformed = || ProtocolError::MalformedResponse {
+            opcode,
+            response: bytes.to_vec(),
+        };
+        if bytes.len() < 3 {
+            return Err(malformed());
+        }
+        if bytes[0] != 0x60 {
+            return Err(invalid("invalid response header"));
+        }
+        if bytes[1] != u8::from(opcode) {
+            return Err(ProtocolError::UnexpectedOpcode {
+                expected: opcode,
+                received: bytes[1],
+            });
+        }
+        let code = ResponseCode::try_from(bytes[2]).map_err(|_| invalid("unknown response code"))?;
+        let len = match code {
+            ResponseCode::Success => 3 + response_len(opcode),
+            // the extended error code follows the result
+            ResponseCode::ExtError => 4,
+            _ => 3,
+        };
+        if bytes.len() != len {
+            return Err(malformed());
+        }
+        match code {
+            ResponseCode::Success => Ok(Response { opcode, bytes }),
+            ResponseCode::ExtError => Err(ProtocolError::Extended {
+                opcode,
+                code: ExtError::from(bytes[3]),
+            }),
+            _ => Err(ProtocolError::Rejected { opcode, code }),
+        }
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    /// The bytes following the result
+    pub fn payload(&self) -> &'b [u8] {
+        &self.bytes[3..]
+    }
+
+    /// Decode the payload, which must be that of a `P::OPCODE` response
+    pub fn decode<P: Payload>(&self) -> Result<P, ProtocolError> {
+        if self.opcode != P::OPCODE {
+            return Err(ProtocolError::UnexpectedOpcode {
+                expected: P::OPCODE,
+                received: self.opcode.into(),
+            });
+        }
+        P::decode(self)
+    }
+
+    /// Fixed size field at offset `at` of the payload
+    fn field<const N: usize>(&self, at: usize) -> Result<[u8; N], ProtocolError> {
+        self.payload()
+            .get(at..at + N)
+            .and_then(|field| field.try_into().ok())
+            .ok_or_else(|| ProtocolError::MalformedResponse {
+                opcode: self.opcode,
+                response: self.bytes.to_vec(),
+            })
+    }
+
+    fn byte(&self, at: usize) -> Result<u8, ProtocolError> {
+        self.field::<1>(at).map(|[b]| b)
+    }
+
+    fn word(&self, at: usize) -> Result<u32, ProtocolError> {
+        self.field(at).map(u32::from_le_bytes)
+    }
+}
+
+/// Typed payload of a successful response
+pub trait Payload: Sized {
+    /// Opcode of the request answered with this payload
+    const OPCODE: OpCode;
+
+    /// Decode the payload of `response`, whose opcode was checked already
+    fn decode(response: &Response) -> Result<Self, ProtocolError>;
+}
+
+/// Response to CrcGet, packet receipt notifications use the same format
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CrcResponse {
+    pub offset: usize,
+    pub crc: u32,
+}
+
+impl Payload for CrcResponse {
+    const OPCODE: OpCode = OpCode::CrcGet;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(CrcResponse {
+            offset: response.word(0)? as usize,
+            crc: response.word(4)?,
+        })
+    }
+}
+
+/// Response to ObjectSelect
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SelectResponse {
+    pub max_size: usize,
+    /// Offset and CRC of the data of the object type received so far
+    pub offset: usize,
+    pub crc: u32,
+}
+
+impl Payload for SelectResponse {
+    const OPCODE: OpCode = OpCode::ObjectSelect;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(SelectResponse {
+            max_size: response.word(0)? as usize,
+            offset: response.word(4)? as usize,
+            crc: response.word(8)?,
+        })
+    }
+}
+
+/// Response to MtuGet
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MtuResponse {
+    pub mtu: usize,
+}
+
+impl Payload for MtuResponse {
+    const OPCODE: OpCode = OpCode::MtuGet;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(MtuResponse {
+            mtu: u16::from_le_bytes(response.field(0)?) as usize,
+        })
+    }
+}
+
+/// Response to ProtocolVersion
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct VersionResponse {
+    pub version: u8,
+}
+
+impl Payload for VersionResponse {
+    const OPCODE: OpCode = OpCode::ProtocolVersion;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(VersionResponse {
+            version: response.byte(0)?,
+        })
+    }
+}
+
+/// Response to Ping, echoing the ID of the request
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PingResponse {
+    pub id: u8,
+}
+
+impl Payload for PingResponse {
+    const OPCODE: OpCode = OpCode::Ping;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(PingResponse { id: response.byte(0)? })
+    }
+}
+
+impl Payload for HardwareVersion {
+    const OPCODE: OpCode = OpCode::HardwareVersion;
+
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(HardwareVersion {
+            part: response.word(0)?,
+            variant: response.word(4)?,
+            rom_size: response.word(8)?,
+            ram_size: response.word(12)?,
+            rom_page_size: response.word(16)?,
+        })
+    }
+}
+
+impl Payload for FirmwareVersion {
+    const OPCODE: OpCode = OpCode::FirmwareVersion;
+
+    /// An empty image slot decodes to [`FirmwareType::Unknown`]
+    fn decode(response: &Response) -> Result<Self, ProtocolError> {
+        Ok(FirmwareVersion {
+            fw_type: FirmwareType::try_from(response.byte(0)?).unwrap_or(FirmwareType::Unknown),
+            version: response.word(1)?,
+            addr: response.word(5)?,
+            len: response.word(9)?,
+        })
+    }
+}
+
 fn crc32(buf: &[u8], init: u32) -> u32 {
     let mut h = crc32fast::Hasher::new_with_initial(init);
     h.update(buf);
     h.finalize()
 }
 
-// More requests are available when `NRF_DFU_PROTOCOL_REDUCED` is not defined
-// in `nRF5_SDK_17.1.0_ddde560/components/libraries/bootloader/dfu/nrf_dfu_req_handler.c`
-struct DfuTarget<'a, T: DfuTransport> {
+/// Client for the control and data points of a DFU target, with a method per request for
+/// embedders that need more (or less) than [`dfu_run_with_options`]
+///
+/// Requests are retried, time out and are cancelled as configured by the [`DfuOptions`] it was
+/// created with. More requests are available when `NRF_DFU_PROTOCOL_REDUCED` is not defined
+/// in `nRF5_SDK_17.1.0_ddde560/components/libraries/bootloader/dfu/nrf_dfu_req_handler.c`
+pub struct DfuTarget<'a, T: DfuTransport + ?Sized> {
     transport: &'a T,
+    /// Size of the data shards written to the data point
+    shard_size: usize,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    shard_retries: usize,
+    object_attempts: usize,
+    max_execute_retries: u32,
+    crc_interval: usize,
+    write_window: usize,
+    events: Option<tokio::sync::mpsc::Sender<DfuEvent>>,
+    confirmed: Option<Arc<AtomicUsize>>,
+    cancel: CancellationToken,
+    /// Control point requests that were retried after a timeout
+    ctrl_retries: AtomicU32,
+    /// CRC mismatches that made data be sent again
+    crc_retries: AtomicU32,
+    /// Executes that were sent again after a timeout or while the target was busy
+    execute_retries: AtomicU32,
 }
 
-impl<'a, T: DfuTransport> DfuTarget<'a, T> {
-    fn verify_header(opcode: u8, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        if bytes.len() < 3 {
-            return Err("invalid response length".into());
+/// Statistics of a successful [`dfu_run_with_options`]
+#[derive(Debug, Clone, Serialize)]
+pub struct DfuSummary {
+    /// Firmware bytes uploaded, less than the firmware length when a transfer was resumed
+    pub bytes: usize,
+    /// Data objects sent, including a resumed one that had to be sent again
+    pub objects: usize,
+    /// Time spent on the data objects, without connecting and the preflight checks
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    /// DFU protocol version the target reported
+    pub protocol_version: Option<u8>,
+    /// ATT MTU the shard size was derived from, see [`DfuOptions::mtu`]
+    pub mtu: usize,
+    pub shard_size: usize,
+    pub ctrl_retries: u32,
+    pub crc_retries: u32,
+    pub execute_retries: u32,
+    /// Target hardware, `None` without [`DfuOptions::preflight`] or if the target does not report it
+    pub hardware: Option<HardwareVersion>,
+    /// Firmware installed before the update, empty without [`DfuOptions::preflight`]
+    pub firmware: Vec<FirmwareVersion>,
+    /// Nothing was transferred as the target already runs the packaged version, see
+    /// [`DfuOptions::skip_current`]
+    pub skipped: bool,
+}
+
+fn serialize_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl DfuSummary {
+    /// Effective upload rate in bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for DfuSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.skipped {
+            return write!(f, "nothing transferred, the target already runs the packaged version");
         }
-        if bytes[0] != 0x60 {
-            return Err("invalid response header".into());
+        write!(
+            f,
+            "{} bytes in {} data objects in {:.1} s ({:.1} kB/s), ",
+            self.bytes,
+            self.objects,
+            self.duration.as_secs_f64(),
+            self.throughput() / 1000.0
+        )?;
+        match self.protocol_version {
+            Some(version) => write!(f, "DFU protocol version {}, ", version)?,
+            None => write!(f, "DFU protocol version unknown, ")?,
+        }
+        write!(
+            f,
+            "MTU {}, {} byte shards, {} control point retries, {} CRC retries, {} execute retries",
+            self.mtu, self.shard_size, self.ctrl_retries, self.crc_retries, self.execute_retries
+        )
+    }
+}
+
+/// Steps of [`dfu_run_with_options`] as they happen, see [`DfuOptions::events`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DfuEvent {
+    /// The control point responds
+    Connected,
+    /// Packet receipt notifications are sent every `value` shards, 0 disables them
+    PrnSet {
+        value: u32,
+    },
+    /// The target holds and accepted the init packet
+    InitPacketSent,
+    /// A data object of `size` bytes was created, `index` counts from the start of the firmware
+    ObjectCreated {
+        index: usize,
+        size: usize,
+    },
+    /// A shard was written, `offset` is the firmware offset right after it
+    ShardWritten {
+        offset: usize,
+    },
+    /// The target confirmed the CRC of the firmware up to `offset`
+    CrcVerified {
+        offset: usize,
+    },
+    ObjectExecuted {
+        index: usize,
+    },
+    Completed,
+    Cancelled,
+    Failed {
+        error: String,
+    },
+}
+
+/// How control point requests that time out are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry
+    pub delay: Duration,
+    /// Factor the delay grows by with each further retry
+    pub backoff: u32,
+    /// Print a warning on every retry
+    pub log: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            delay: Duration::from_millis(100),
+            backoff: 2,
+            log: true,
+        }
+    }
+}
+
+/// How long the target may take to respond, before the request is retried or given up on
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// Control point requests without a timeout of their own
+    pub request: Duration,
+    /// CRC requests and packet receipt notifications
+    pub crc: Duration,
+    /// Creating an object, which makes the bootloader erase the flash pages for a data object
+    pub create: Duration,
+    /// Executing an object, which makes the bootloader write (and possibly erase) flash
+    pub execute: Duration,
+    /// Data point writes
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request: Duration::from_secs(1),
+            crc: Duration::from_secs(1),
+            create: Duration::from_millis(2500),
+            execute: Duration::from_secs(10),
+            write: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Timeouts {
+    fn request(&self, opcode: OpCode) -> Duration {
+        match opcode {
+            OpCode::ObjectExecute => self.execute,
+            OpCode::ObjectCreate => self.create,
+            OpCode::CrcGet => self.crc,
+            _ => self.request,
+        }
+    }
+}
+
+/// Options for [`dfu_run_with_options`]
+#[derive(Debug, Clone)]
+pub struct DfuOptions {
+    /// Query the hardware and firmware versions of the target first, without them the size and
+    /// compatibility checks are skipped
+    pub preflight: bool,
+    /// Check the firmware length against the flash size reported by the target first
+    pub check_size: bool,
+    /// Check the hardware and softdevice requirements of the init packet against the target
+    pub check_compat: bool,
+    /// With a nonzero `prn` the target sends a packet receipt notification every `prn` shards instead of
+    /// the CRC being requested, which is noticeably faster
+    pub prn: u32,
+    /// Without receipt notifications the CRC is requested every `crc_interval` shards and at the end of
+    /// each data object; fewer round trips are faster, but more data is re-sent after an error
+    pub crc_interval: usize,
+    /// With receipt notifications, shards written ahead of the last confirmed one; at least `prn`, more
+    /// keep the link busy while a notification is on its way
+    pub write_window: usize,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    /// Times the rest of a data object is re-sent after shards got lost
+    pub shard_retries: usize,
+    /// Times a data object is created and sent before giving up
+    pub object_attempts: usize,
+    /// Transfer nothing if the target already runs the packaged application version or a newer one,
+    /// which needs [`DfuOptions::preflight`]; the summary is then [`DfuSummary::skipped`]
+    pub skip_current: bool,
+    /// ATT MTU of the link, by default what the transport reports or else what the target reports
+    pub mtu: Option<usize>,
+    /// Start from scratch even if the target holds the init packet or firmware data of an earlier
+    /// transfer, e.g. a different build whose beginning happens to match
+    pub force_full: bool,
+    /// Times executing an object is retried after it timed out or the target failed it, e.g. while
+    /// busy erasing flash, see [`DfuTarget::execute_object`]
+    pub execute_retries: u32,
+    /// Receives a [`DfuEvent`] for every step; events are dropped rather than stalling the transfer
+    /// when the channel is full
+    pub events: Option<tokio::sync::mpsc::Sender<DfuEvent>>,
+    /// Set to the firmware offset up to which the target confirmed the data as soon as it does;
+    /// unlike [`DfuOptions::events`] it misses no update, e.g. to tell how far a failed run got
+    pub confirmed: Option<Arc<AtomicUsize>>,
+    /// Stops the transfer between shards and control point requests once cancelled, the target is
+    /// then told to abort and the run fails with [`DfuError::Cancelled`]
+    pub cancel: Option<CancellationToken>,
+}
+
+impl Default for DfuOptions {
+    fn default() -> Self {
+        DfuOptions {
+            preflight: true,
+            check_size: true,
+            check_compat: true,
+            prn: 0,
+            crc_interval: 1,
+            write_window: 0,
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            shard_retries: 3,
+            object_attempts: 3,
+            skip_current: false,
+            mtu: None,
+            force_full: false,
+            execute_retries: 2,
+            events: None,
+            confirmed: None,
+            cancel: None,
+        }
+    }
+}
+
+impl<'a, T: DfuTransport + ?Sized> DfuTarget<'a, T> {
+    /// Client for the target behind `transport`, writing the smallest possible shards until
+    /// [`DfuTarget::set_shard_size`] is called
+    pub fn new(transport: &'a T, options: &DfuOptions) -> Self {
+        DfuTarget {
+            transport,
+            shard_size: MIN_SHARD_SIZE,
+            retry: options.retry.clone(),
+            timeouts: options.timeouts.clone(),
+            shard_retries: options.shard_retries,
+            object_attempts: options.object_attempts,
+            max_execute_retries: options.execute_retries,
+            crc_interval: options.crc_interval.max(1),
+            write_window: options.write_window,
+            events: options.events.clone(),
+            confirmed: options.confirmed.clone(),
+            cancel: options.cancel.clone().unwrap_or_default(),
+            ctrl_retries: AtomicU32::new(0),
+            crc_retries: AtomicU32::new(0),
+            execute_retries: AtomicU32::new(0),
+        }
+    }
+
+    /// Size of the data shards written by [`dfu_run_with_options`], i.e. what fits the MTU
+    pub fn shard_size(&self) -> usize {
+        self.shard_size
+    }
+
+    pub fn set_shard_size(&mut self, shard_size: usize) {
+        self.shard_size = shard_size.max(1);
+    }
+
+    fn emit(&self, event: DfuEvent) {
+        if let Some(events) = &self.events {
+            // a slow consumer loses events, the transfer goes on
+            let _ = events.try_send(event);
+        }
+    }
+
+    /// Report that the target holds the firmware up to `offset` with a matching CRC
+    fn confirm(&self, offset: usize) {
+        if let Some(confirmed) = &self.confirmed {
+            confirmed.store(offset, Ordering::Relaxed);
         }
-        if bytes[1] != opcode {
-            return Err("invalid response opcode".into());
+        self.emit(DfuEvent::CrcVerified { offset });
+    }
+
+    /// Run `future` unless the transfer is cancelled first
+    async fn cancellable<F: std::future::Future>(&self, future: F) -> Result<F::Output, DfuError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(DfuError::Cancelled),
+            output = future => Ok(output),
         }
-        let result = ResponseCode::try_from(bytes[2])?;
-        if result != ResponseCode::Success {
-            return Err(format!("{:?}", result).into());
+    }
+
+    /// Write `bytes` to the data point, `offset` is where they start in the object data, for the logs only
+    pub async fn write_data(&self, bytes: &[u8], offset: usize) -> Result<(), DfuError> {
+        trace!("data write of {} bytes at {}", bytes.len(), offset);
+        Ok(self
+            .cancellable(self.transport.write_data(bytes, self.timeouts.write))
+            .await??)
+    }
+
+    /// Single control point request without retries
+    async fn exchange(&self, opcode: OpCode, request: &[u8]) -> Result<Vec<u8>, TransportError> {
+        debug!("{:?} request: {}", opcode, to_hex(request));
+        let response = self
+            .transport
+            .request_ctrl(request, self.timeouts.request(opcode))
+            .await;
+        match &response {
+            Ok(response) => debug!("{:?} response: {}", opcode, to_hex(response)),
+            Err(e) => debug!("{:?} request failed: {}", opcode, e),
         }
+        response
+    }
+
+    /// Send `opcode` with its parameters to the control point
+    async fn request_ctrl(&self, opcode: OpCode, params: &[u8]) -> Result<Vec<u8>, DfuError> {
+        let mut request = vec![opcode.into()];
+        request.extend_from_slice(params);
+        let mut delay = self.retry.delay;
+        for retry in 0..=self.retry.retries {
+            if retry > 0 {
+                if self.retry.log {
+                    warn!(
+                        "{:?} request timed out, retrying in {} ms ({}/{})",
+                        opcode,
+                        delay.as_millis(),
+                        retry,
+                        self.retry.retries
+                    );
+                }
+                self.ctrl_retries.fetch_add(1, Ordering::Relaxed);
+                self.cancellable(tokio::time::sleep(delay)).await?;
+                delay *= self.retry.backoff;
+            }
+            match self.cancellable(self.exchange(opcode, &request)).await? {
+                Err(TransportError::Timeout) => continue,
+                res => return Ok(res?),
+            }
+        }
+        Err(ProtocolError::NoResponse {
+            opcode,
+            attempts: self.retry.retries + 1,
+        }
+        .into())
+    }
+
+    /// Have the target send a packet receipt notification every `value` shards, 0 disables them
+    pub async fn set_prn(&self, value: u32) -> Result<(), DfuError> {
+        let opcode = OpCode::ReceiptNotifSet;
+        let response = self.request_ctrl(opcode, &value.to_le_bytes()).await?;
+        Response::parse(opcode, &response)?;
+        self.emit(DfuEvent::PrnSet { value });
         Ok(())
     }
 
-    async fn write_data(&self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        self.transport.write_data(bytes).await
+    /// Offset and CRC of the data of the selected object type
+    pub async fn get_crc(&self) -> Result<(usize, u32), DfuError> {
+        let response = self.request_ctrl(OpCode::CrcGet, &[]).await?;
+        Ok(Self::parse_crc(&response)?)
+    }
+
+    /// Wait for the next packet receipt notification
+    pub async fn receive_crc(&self) -> Result<(usize, u32), DfuError> {
+        let deadline = tokio::time::Instant::now() + self.timeouts.crc;
+        loop {
+            let timeout = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let notification = self.cancellable(self.transport.receive_ctrl(timeout)).await??;
+            // e.g. the late response to a request that timed out
+            if notification.get(1) != Some(&OpCode::CrcGet.into()) {
+                debug!("ignoring control point notification: {}", to_hex(&notification));
+                continue;
+            }
+            debug!("packet receipt notification: {}", to_hex(&notification));
+            return Ok(Self::parse_crc(&notification)?);
+        }
+    }
+
+    /// Decode a CRC response, packet receipt notifications use the same format
+    fn parse_crc(response: &[u8]) -> Result<(usize, u32), ProtocolError> {
+        let crc: CrcResponse = Response::parse(OpCode::CrcGet, response)?.decode()?;
+        Ok((crc.offset, crc.crc))
+    }
+
+    /// Select the object type the following requests refer to, returns the maximum object size
+    /// and the offset and CRC of the data received so far
+    pub async fn select_object(&self, obj_type: Object) -> Result<(usize, usize, u32), DfuError> {
+        let opcode = OpCode::ObjectSelect;
+        let response = self.request_ctrl(opcode, &[obj_type.into()]).await?;
+        let select: SelectResponse = Response::parse(opcode, &response)?.decode()?;
+        Ok((select.max_size, select.offset, select.crc))
     }
 
-    async fn request_ctrl(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-        for _retry in 0..3 {
-            match self.transport.request_ctrl(bytes).await {
-                Err(e) => {
-                    if e.is::<tokio::time::error::Elapsed>() {
-                        // response timed out, retry
-                        continue;
-                    } else {
-                        return Err(e);
+    /// Create an object of `len` bytes, discarding a command object or data object that was not executed
+    pub async fn create_object(&self, obj_type: Object, len: usize) -> Result<(), DfuError> {
+        let opcode = OpCode::ObjectCreate;
+        let mut params: Vec<u8> = vec![obj_type.into()];
+        params.extend_from_slice(&(len as u32).to_le_bytes());
+        let response = self.request_ctrl(opcode, &params).await?;
+        Response::parse(opcode, &response)?;
+        Ok(())
+    }
+
+    /// Validate and store the current object; executing the last data object activates the firmware
+    pub async fn execute(&self) -> Result<(), DfuError> {
+        let opcode = OpCode::ObjectExecute;
+        let response = self.request_ctrl(opcode, &[]).await?;
+        Response::parse(opcode, &response)?;
+        Ok(())
+    }
+
+    /// Execute the current object of `obj_type`, which holds data up to `offset` with CRC `checksum`.
+    ///
+    /// Unlike [`DfuTarget::execute`], a timeout or an OperationFailed result, e.g. while the bootloader
+    /// is busy erasing flash, is retried after a delay, but only once the target has confirmed that the
+    /// object is still intact. An execute that timed out may still have taken effect, the target then
+    /// rejects the next one as not permitted, which counts as success.
+    pub async fn execute_object(&self, obj_type: Object, offset: usize, checksum: u32) -> Result<(), DfuError> {
+        let opcode = OpCode::ObjectExecute;
+        let mut delay = self.retry.delay;
+        let mut timed_out = false;
+        let mut retry = 0;
+        loop {
+            let error: DfuError = match self.cancellable(self.exchange(opcode, &[opcode.into()])).await? {
+                Err(TransportError::Timeout) => {
+                    timed_out = true;
+                    ProtocolError::NoResponse {
+                        opcode,
+                        attempts: retry + 1,
                     }
+                    .into()
                 }
-                Ok(r) => {
-                    return Ok(r);
-                }
+                Err(e) => return Err(e.into()),
+                Ok(response) => match Response::parse(opcode, &response) {
+                    Ok(_) => return Ok(()),
+                    Err(ProtocolError::Rejected {
+                        code: ResponseCode::OperationNotPermitted,
+                        ..
+                    }) if timed_out => {
+                        info!(
+                            "Execute of {:?} object at {} bytes took effect before",
+                            obj_type, offset
+                        );
+                        return Ok(());
+                    }
+                    Err(
+                        e @ ProtocolError::Rejected {
+                            code: ResponseCode::OperationFailed,
+                            ..
+                        },
+                    ) => e.into(),
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            if retry == self.max_execute_retries {
+                return Err(error);
+            }
+            warn!(
+                "executing the {:?} object at {} bytes failed: {}, retrying in {} ms ({}/{})",
+                obj_type,
+                offset,
+                error,
+                delay.as_millis(),
+                retry + 1,
+                self.max_execute_retries
+            );
+            self.execute_retries.fetch_add(1, Ordering::Relaxed);
+            self.cancellable(tokio::time::sleep(delay)).await?;
+            delay *= self.retry.backoff;
+            // executing an object that lost data would fail for good
+            let (_, actual_offset, actual_crc) = self.select_object(obj_type).await?;
+            if (actual_offset, actual_crc) != (offset, checksum) {
+                warn!(
+                    "target holds {} bytes with CRC {:#010x} instead of {} bytes with CRC {:#010x}, not retrying",
+                    actual_offset, actual_crc, offset, checksum
+                );
+                return Err(error);
+            }
+            retry += 1;
+        }
+    }
+
+    /// Query the target hardware, `None` if the bootloader does not support the request
+    pub async fn hardware_version(&self) -> Result<Option<HardwareVersion>, DfuError> {
+        let opcode = OpCode::HardwareVersion;
+        let response = self.request_ctrl(opcode, &[]).await?;
+        match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported,
+                ..
+            }) => Ok(None),
+            res => Ok(Some(res?.decode()?)),
+        }
+    }
+
+    /// Check that the control point responds at all, without the retries of regular requests.
+    /// Bootloaders without ping support are assumed to be responsive.
+    pub async fn ping(&self) -> Result<(), DfuError> {
+        use std::hash::{BuildHasher, Hasher};
+        let opcode = OpCode::Ping;
+        let id = std::collections::hash_map::RandomState::new().build_hasher().finish() as u8;
+        let response = self
+            .exchange(opcode, &[opcode.into(), id])
+            .await
+            .map_err(|e| ProtocolError::Unresponsive(e.to_string()))?;
+        let ping: PingResponse = match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported,
+                ..
+            }) => {
+                info!("Target does not support ping, assuming it is responsive");
+                return Ok(());
+            }
+            res => res?.decode()?,
+        };
+        if ping.id != id {
+            return Err(
+                ProtocolError::Unresponsive(format!("ping {:#04x} answered with {:02x?}", id, response)).into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Tell the bootloader to discard the transfer, without retries as the link may already be dead.
+    /// Bootloaders without abort support only discard it on their inactivity timeout.
+    pub async fn abort(&self) -> Result<(), DfuError> {
+        let opcode = OpCode::Abort;
+        let response = self.exchange(opcode, &[opcode.into()]).await?;
+        match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported,
+                ..
+            }) => {
+                info!("Target does not support abort");
+                Ok(())
+            }
+            res => res.map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    /// Query the DFU protocol version, which must be one we understand; `None` if the bootloader does
+    /// not support the request, like those built with `NRF_DFU_PROTOCOL_REDUCED`
+    pub async fn protocol_version(&self) -> Result<Option<u8>, DfuError> {
+        let opcode = OpCode::ProtocolVersion;
+        let response = self.request_ctrl(opcode, &[]).await?;
+        let VersionResponse { version } = match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported,
+                ..
+            }) => return Ok(None),
+            res => res?.decode()?,
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion { version, response }.into());
+        }
+        Ok(Some(version))
+    }
+
+    /// Query the ATT MTU, `None` if the bootloader does not support the request
+    pub async fn mtu(&self) -> Result<Option<usize>, DfuError> {
+        let opcode = OpCode::MtuGet;
+        let response = self.request_ctrl(opcode, &[]).await?;
+        match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported,
+                ..
+            }) => Ok(None),
+            res => Ok(Some(res?.decode::<MtuResponse>()?.mtu)),
+        }
+    }
+
+    /// Query the firmware installed in the image slot `index`, `None` for an empty slot
+    /// or if the bootloader does not support the request
+    pub async fn firmware_version(&self, index: u8) -> Result<Option<FirmwareVersion>, DfuError> {
+        let opcode = OpCode::FirmwareVersion;
+        let response = self.request_ctrl(opcode, &[index]).await?;
+        let fw: FirmwareVersion = match Response::parse(opcode, &response) {
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::OpCodeNotSupported | ResponseCode::InvalidParameter,
+                ..
+            }) => return Ok(None),
+            res => res?.decode()?,
+        };
+        Ok(Some(fw).filter(|fw| fw.fw_type != FirmwareType::Unknown))
+    }
+
+    /// Query all image slots, stopping at the first empty slot or error response
+    pub async fn firmware_versions(&self) -> Result<Vec<FirmwareVersion>, DfuError> {
+        let mut versions = Vec::new();
+        for index in 0..=u8::MAX {
+            match self.firmware_version(index).await {
+                Ok(Some(fw)) => versions.push(fw),
+                Ok(None) => break,
+                Err(DfuError::Protocol(ProtocolError::Rejected { .. } | ProtocolError::Extended { .. })) => break,
+                Err(e) => return Err(e),
             }
         }
-        Err("No response after multiple tries".into())
+        Ok(versions)
     }
 
-    async fn set_prn(&self, value: u32) -> Result<(), Box<dyn Error>> {
-        let opcode: u8 = OpCode::ReceiptNotifSet.into();
-        let mut payload: Vec<u8> = vec![opcode];
-        payload.extend_from_slice(&value.to_le_bytes());
-        let response = self.request_ctrl(&payload).await?;
-        Self::verify_header(opcode, &response)?;
+    /// Check that the firmware can fit into the target's flash at all
+    fn check_size(hw: &HardwareVersion, fw_len: usize) -> Result<(), ProtocolError> {
+        // the bootloader rejects images not fitting next to softdevice and bootloader,
+        // but anything larger than the flash minus the MBR cannot work in any case
+        let available = (hw.rom_size as usize).saturating_sub(MBR_SIZE);
+        if fw_len > available {
+            return Err(ProtocolError::Incompatible(format!(
+                "firmware of {} bytes does not fit into {} bytes of flash on nRF{:x}",
+                fw_len, available, hw.part
+            )));
+        }
         Ok(())
     }
 
-    async fn get_crc(&self) -> Result<(usize, u32), Box<dyn Error>> {
-        let opcode: u8 = OpCode::CrcGet.into();
-        let response = self.request_ctrl(&[opcode]).await?;
-        Self::verify_header(opcode, &response)?;
-        let offset = u32::from_le_bytes(response[3..7].try_into()?);
-        let checksum = u32::from_le_bytes(response[7..11].try_into()?);
-        Ok((offset as usize, checksum))
-    }
-
-    async fn select_object(&self, obj_type: Object) -> Result<(usize, usize, u32), Box<dyn Error>> {
-        let opcode: u8 = OpCode::ObjectSelect.into();
-        let arg: u8 = obj_type.into();
-        let response = self.request_ctrl(&[opcode, arg]).await?;
-        Self::verify_header(opcode, &response)?;
-        let max_size = u32::from_le_bytes(response[3..7].try_into()?);
-        let offset = u32::from_le_bytes(response[7..11].try_into()?);
-        let checksum = u32::from_le_bytes(response[11..15].try_into()?);
-        Ok((max_size as usize, offset as usize, checksum))
-    }
-
-    async fn create_object(&self, obj_type: Object, len: usize) -> Result<(), Box<dyn Error>> {
-        let opcode: u8 = OpCode::ObjectCreate.into();
-        let mut payload: Vec<u8> = vec![opcode, obj_type.into()];
-        payload.extend_from_slice(&(len as u32).to_le_bytes());
-        let response = self.request_ctrl(&payload).await?;
-        Self::verify_header(opcode, &response)?;
+    /// Check the hardware and softdevice requirements of the init packet against the target
+    fn check_compat(
+        hw: Option<&HardwareVersion>,
+        firmware: &[FirmwareVersion],
+        init: &InitPacket,
+    ) -> Result<(), ProtocolError> {
+        if let (Some(hw), Some(hw_version)) = (hw, init.hw_version) {
+            // hw_version is conventionally the chip family, e.g. 52 for part 0x52840
+            let family = format!("{:x}", hw.part).get(..2).and_then(|f| f.parse::<u32>().ok());
+            if family.is_some_and(|family| family != hw_version) {
+                return Err(ProtocolError::Incompatible(format!(
+                    "hardware mismatch: package requires hw_version {}, target is nRF{:x}",
+                    hw_version, hw.part
+                )));
+            }
+        }
+
+        if init.sd_req.is_empty() || init.sd_req.contains(&SD_REQ_ANY) {
+            return Ok(());
+        }
+        let softdevice = firmware.iter().find(|fw| fw.fw_type == FirmwareType::Softdevice);
+        let sd_req: Vec<String> = init.sd_req.iter().map(|id| format!("{:#06x}", id)).collect();
+        let sd = match softdevice {
+            // 0x00 in sd_req allows updating targets without softdevice
+            None if init.sd_req.contains(&0) => return Ok(()),
+            None => {
+                info!("Target does not report a softdevice, skipping softdevice check");
+                return Ok(());
+            }
+            Some(sd) => sd,
+        };
+        let fwids = match SOFTDEVICE_FWIDS.iter().find(|(version, _)| *version == sd.version) {
+            Some((_, fwids)) => fwids,
+            None => {
+                info!("Unknown softdevice version {}, skipping softdevice check", sd.version);
+                return Ok(());
+            }
+        };
+        if !fwids.iter().any(|id| init.sd_req.contains(id)) {
+            let fwids: Vec<String> = fwids.iter().map(|id| format!("{:#06x}", id)).collect();
+            return Err(ProtocolError::Incompatible(format!(
+                "softdevice mismatch: package requires one of [{}], target runs softdevice {} ({})",
+                sd_req.join(", "),
+                sd.version,
+                fwids.join(" or ")
+            )));
+        }
         Ok(())
     }
 
-    async fn execute(&self) -> Result<(), Box<dyn Error>> {
-        let opcode: u8 = OpCode::ObjectExecute.into();
-        let response = self.request_ctrl(&[opcode]).await?;
-        Self::verify_header(opcode, &response)?;
+    /// Create, fill and execute the data object `index` holding `data`, which starts at `offset` of the
+    /// firmware with `checksum` the CRC of the data before it; returns the CRC including `data`
+    ///
+    /// With `prn` 0 the CRC is requested every [`DfuOptions::crc_interval`] shards, otherwise the packet
+    /// receipt notification the target sends after every `prn` shards is checked.
+    async fn send_object(
+        &self,
+        index: usize,
+        data: &[u8],
+        offset: usize,
+        checksum: u32,
+        fw_len: usize,
+        prn: u32,
+    ) -> Result<u32, DfuError> {
+        let attempts = self.object_attempts.max(1);
+        for attempt in 1..=attempts {
+            self.create_object(Object::Data, data.len()).await?;
+            self.emit(DfuEvent::ObjectCreated {
+                index,
+                size: data.len(),
+            });
+            if let Some(checksum) = self.write_object(data, offset, checksum, fw_len, prn).await? {
+                self.execute_object(Object::Data, offset + data.len(), checksum).await?;
+                self.emit(DfuEvent::ObjectExecuted { index });
+                return Ok(checksum);
+            }
+            if attempt < attempts {
+                warn!(
+                    "data object at {} bytes is corrupted on the target (attempt {}/{}), recreating it",
+                    offset, attempt, attempts
+                );
+            }
+        }
+        Err(ProtocolError::TransferFailed { offset, attempts }.into())
+    }
+
+    /// Write the shards of a freshly created data object, re-sending from the last offset the target agrees
+    /// on when a shard got lost. `None` if the target's data can not be brought back in sync.
+    async fn write_object(
+        &self,
+        data: &[u8],
+        offset: usize,
+        checksum: u32,
+        fw_len: usize,
+        prn: u32,
+    ) -> Result<Option<u32>, DfuError> {
+        let mut pos = 0;
+        let mut crc = checksum;
+        // position and CRC the target last confirmed
+        let mut verified = (0, checksum);
+        let mut rewinds = 0;
+        while pos < data.len() {
+            let reported;
+            (pos, crc, reported) = if prn == 0 {
+                self.write_shards(data, offset, pos, crc).await?
+            } else {
+                self.write_pipelined(data, offset, pos, crc, fw_len, prn, &mut verified)
+                    .await?
+            };
+            if reported == (offset + pos, crc) {
+                verified = (pos, crc);
+                self.confirm(offset + pos);
+                info!("Uploaded {}/{} bytes", offset + pos, fw_len);
+                continue;
+            }
+
+            // the target's data is only usable if it is a prefix of ours, typically the part
+            // confirmed last when everything after it got lost
+            let (target_offset, target_crc) = reported;
+            self.crc_retries.fetch_add(1, Ordering::Relaxed);
+            rewinds += 1;
+            if rewinds > self.shard_retries || target_offset < offset || target_offset > offset + pos {
+                return Ok(None);
+            }
+            let agreed = target_offset - offset;
+            if (agreed, target_crc) != verified && crc32(&data[..agreed], checksum) != target_crc {
+                return Ok(None);
+            }
+            warn!(
+                "target has {} of {} bytes, re-sending from there",
+                target_offset,
+                offset + pos
+            );
+            pos = agreed;
+            crc = target_crc;
+        }
+        Ok(Some(crc))
+    }
+
+    /// Write shards from `pos` on until the CRC is due, every [`DfuOptions::crc_interval`] shards and at
+    /// the end of the object; returns the position and CRC written and what the target reports
+    async fn write_shards(
+        &self,
+        data: &[u8],
+        offset: usize,
+        mut pos: usize,
+        mut crc: u32,
+    ) -> Result<(usize, u32, (usize, u32)), DfuError> {
+        loop {
+            let shard = &data[pos..(pos + self.shard_size).min(data.len())];
+            let n = pos / self.shard_size;
+            crc = crc32(shard, crc);
+            self.write_data(shard, offset + pos).await?;
+            pos += shard.len();
+            self.emit(DfuEvent::ShardWritten { offset: offset + pos });
+            if (n + 1).is_multiple_of(self.crc_interval) || pos == data.len() {
+                return Ok((pos, crc, self.get_crc().await?));
+            }
+        }
+    }
+
+    /// Write shards from `pos` on without waiting for each packet receipt notification, up to
+    /// [`DfuOptions::write_window`] shards ahead of the last one. Stops at the end of the object or the
+    /// first receipt that does not match, once the writes in flight are done; returns the position and
+    /// CRC written and what the target reports then.
+    #[allow(clippy::too_many_arguments)]
+    async fn write_pipelined(
+        &self,
+        data: &[u8],
+        offset: usize,
+        start: usize,
+        checksum: u32,
+        fw_len: usize,
+        prn: u32,
+        verified: &mut (usize, u32),
+    ) -> Result<(usize, u32, (usize, u32)), DfuError> {
+        let prn = prn as usize;
+        // shards are only counted once received, so after a rewind the receipt counter (which
+        // restarts with every created object) matches the position again
+        let window = tokio::sync::Semaphore::new(self.write_window.max(prn));
+        let (expected_tx, mut expected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let writer = async {
+            let (mut pos, mut crc) = (start, checksum);
+            while pos < data.len() {
+                // the receiver closes the window when it stops
+                match window.acquire().await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                let shard = &data[pos..(pos + self.shard_size).min(data.len())];
+                let n = pos / self.shard_size;
+                crc = crc32(shard, crc);
+                self.write_data(shard, offset + pos).await?;
+                pos += shard.len();
+                self.emit(DfuEvent::ShardWritten { offset: offset + pos });
+                if (n + 1).is_multiple_of(prn) {
+                    let _ = expected_tx.send((pos, crc));
+                }
+            }
+            drop(expected_tx);
+            Ok::<_, DfuError>((pos, crc))
+        };
+        let receiver = async {
+            while let Some((pos, crc)) = expected_rx.recv().await {
+                let received = loop {
+                    match self.receive_crc().await {
+                        // left over from before a rewind
+                        Ok((off, _)) if off <= offset + start && pos > start => continue,
+                        res => break res,
+                    }
+                };
+                match received {
+                    Ok(received) if received == (offset + pos, crc) => {
+                        *verified = (pos, crc);
+                        // the end of the object is reported by the caller
+                        if pos < data.len() {
+                            self.confirm(offset + pos);
+                            info!("Uploaded {}/{} bytes", offset + pos, fw_len);
+                        }
+                        window.add_permits(prn);
+                    }
+                    Ok(_) => break,
+                    Err(DfuError::Cancelled) => {
+                        window.close();
+                        return Err(DfuError::Cancelled);
+                    }
+                    Err(e) => {
+                        warn!("no packet receipt notification at {} bytes: {}", offset + pos, e);
+                        break;
+                    }
+                }
+            }
+            window.close();
+            Ok(())
+        };
+        let (written, received) = tokio::join!(writer, receiver);
+        let (pos, crc) = written?;
+        received?;
+        if *verified == (pos, crc) {
+            return Ok((pos, crc, (offset + pos, crc)));
+        }
+        Ok((pos, crc, self.get_crc().await?))
+    }
+
+    async fn verify_crc(&self, offset: usize, checksum: u32) -> Result<(), DfuError> {
+        Self::check_crc(self.get_crc().await?, offset, checksum)?;
+        self.emit(DfuEvent::CrcVerified { offset });
         Ok(())
     }
 
-    async fn verify_crc(&self, offset: usize, checksum: u32) -> Result<(), Box<dyn Error>> {
-        let (off, crc) = self.get_crc().await?;
+    /// Write the init packet to the created command object in shards, as some stacks silently
+    /// truncate writes longer than the ATT payload, and check its CRC
+    async fn write_command(&self, init_pkt: &[u8]) -> Result<(), DfuError> {
+        for (n, shard) in init_pkt.chunks(self.shard_size).enumerate() {
+            self.write_data(shard, n * self.shard_size).await?;
+        }
+        self.verify_crc(init_pkt.len(), crc32(init_pkt, 0)).await
+    }
+
+    /// Compare the offset and CRC reported by the target with the expected ones
+    fn check_crc((off, crc): (usize, u32), offset: usize, checksum: u32) -> Result<(), ProtocolError> {
         if offset != off {
-            return Err("Length mismatch".into());
+            return Err(ProtocolError::LengthMismatch {
+                expected: offset,
+                actual: off,
+            });
         }
         if checksum != crc {
-            return Err("CRC mismatch".into());
+            return Err(ProtocolError::CrcMismatch { offset });
         }
         Ok(())
     }
 }
 
+/// Turn the extended error of an execute that failed validation into an error saying what to look at,
+/// querying the target for the versions the check compared the package with
+async fn explain_validation<T: DfuTransport + ?Sized>(
+    target: &DfuTarget<'_, T>,
+    code: ExtError,
+    init_pkt: &[u8],
+) -> DfuError {
+    let opcode = OpCode::ObjectExecute;
+    let init = InitPacket::parse(init_pkt).ok();
+    let unknown = || "unknown".to_string();
+    let details = match code {
+        ExtError::VerificationFailed => match init.as_ref().map(|init| init.signature.is_some()) {
+            Some(false) => "the init packet is not signed, but the bootloader requires a signature".to_string(),
+            _ => "the package is most likely signed with a different key than the one built into the bootloader, \
+                  verify the init packet signature against the bootloader's public key"
+                .to_string(),
+        },
+        ExtError::FwVersionFailure => {
+            let fw_type = match init.as_ref().and_then(|init| init.fw_type) {
+                Some(FwType::Softdevice) => FirmwareType::Softdevice,
+                Some(FwType::Bootloader | FwType::SoftdeviceBootloader) => FirmwareType::Bootloader,
+                _ => FirmwareType::Application,
+            };
+            let installed = target
+                .firmware_versions()
+                .await
+                .ok()
+                .and_then(|firmware| firmware.into_iter().find(|fw| fw.fw_type == fw_type))
+                .map_or_else(unknown, |fw| fw.version.to_string());
+            let packaged = init
+                .as_ref()
+                .and_then(|init| init.fw_version)
+                .map_or_else(unknown, |version| version.to_string());
+            format!(
+                "the package has {:?} version {}, the target runs version {}; the bootloader only accepts newer \
+                 versions, build the package with a higher fw_version",
+                fw_type, packaged, installed
+            )
+        }
+        ExtError::HwVersionFailure => {
+            let target_hw = match target.hardware_version().await {
+                Ok(Some(hw)) => hw.to_string(),
+                _ => unknown(),
+            };
+            let packaged = init
+                .as_ref()
+                .and_then(|init| init.hw_version)
+                .map_or_else(unknown, |version| version.to_string());
+            format!(
+                "the package requires hw_version {}, the target is {}",
+                packaged, target_hw
+            )
+        }
+        _ => return ProtocolError::Extended { opcode, code }.into(),
+    };
+    ProtocolError::ValidationFailed { opcode, code, details }.into()
+}
+
+/// Fill `buf` from `reader`, returning a shorter slice only at the end of the stream
+fn read_chunk<'b>(reader: &mut impl Read, buf: &'b mut [u8]) -> std::io::Result<&'b [u8]> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(&buf[..len])
+}
+
+/// Query the target hardware, `None` for bootloaders built with the reduced protocol
+pub async fn hardware_version<T: DfuTransport + ?Sized>(transport: &T) -> Result<Option<HardwareVersion>, DfuError> {
+    let target = DfuTarget::new(transport, &DfuOptions::default());
+    target.hardware_version().await
+}
+
+/// Query the firmware installed in all image slots of the target, e.g. to decide whether an update is needed
+pub async fn firmware_versions<T: DfuTransport + ?Sized>(transport: &T) -> Result<Vec<FirmwareVersion>, DfuError> {
+    let target = DfuTarget::new(transport, &DfuOptions::default());
+    target.firmware_versions().await
+}
+
+/// Abort the DFU procedure on a best effort basis, e.g. when the user interrupts the update,
+/// so that the bootloader does not sit in a half-written state until its inactivity timeout
+pub async fn abort<T: DfuTransport + ?Sized>(transport: &T) -> Result<(), DfuError> {
+    let target = DfuTarget::new(transport, &DfuOptions::default());
+    target.abort().await
+}
+
 /// Run DFU procedure as specified in
 /// [DFU Protocol](https://infocenter.nordicsemi.com/topic/sdk_nrf5_v17.1.0/lib_dfu_transport_ble.html)
-pub async fn dfu_run(transport: &impl DfuTransport, init_pkt: &[u8], fw_pkt: &[u8]) -> Result<(), Box<dyn Error>> {
-    let target = DfuTarget { transport };
+///
+/// The firmware is pulled from `fw_pkt` one data object at a time, `fw_len` is its total length.
+/// A transfer interrupted earlier is resumed from the data the target already holds if its CRC matches.
+/// If the procedure fails, the target is told to abort it, unless the link failed
+/// ([`DfuError::is_link_error`]): the target then keeps the data for resuming after reconnecting.
+pub async fn dfu_run<T: DfuTransport + ?Sized>(
+    transport: &T,
+    init_pkt: &[u8],
+    fw_pkt: impl Read,
+    fw_len: usize,
+) -> Result<DfuSummary, DfuError> {
+    dfu_run_with_options(transport, init_pkt, fw_pkt, fw_len, &DfuOptions::default()).await
+}
+
+/// [`dfu_run`] tuned by `options`
+pub async fn dfu_run_with_options<T: DfuTransport + ?Sized>(
+    transport: &T,
+    init_pkt: &[u8],
+    fw_pkt: impl Read,
+    fw_len: usize,
+    options: &DfuOptions,
+) -> Result<DfuSummary, DfuError> {
+    let res = dfu_transfer(transport, init_pkt, fw_pkt, fw_len, options).await;
+    let target = DfuTarget::new(transport, options);
+    let res = match res {
+        Err(DfuError::Protocol(ProtocolError::Extended {
+            opcode: OpCode::ObjectExecute,
+            code,
+        })) => Err(explain_validation(&target, code, init_pkt).await),
+        res => res,
+    };
+    match &res {
+        Ok(_) => target.emit(DfuEvent::Completed),
+        Err(DfuError::Cancelled) => {
+            let _ = target.abort().await;
+            target.emit(DfuEvent::Cancelled);
+        }
+        Err(e) => {
+            // the original error is what matters, the link may well be dead by now
+            if !e.is_link_error() {
+                let _ = target.abort().await;
+            }
+            target.emit(DfuEvent::Failed { error: e.to_string() });
+        }
+    }
+    res
+}
+
+async fn dfu_transfer<T: DfuTransport + ?Sized>(
+    transport: &T,
+    init_pkt: &[u8],
+    mut fw_pkt: impl Read,
+    fw_len: usize,
+    options: &DfuOptions,
+) -> Result<DfuSummary, DfuError> {
+    let DfuOptions {
+        preflight,
+        check_size,
+        check_compat,
+        prn,
+        skip_current,
+        force_full,
+        ..
+    } = *options;
+    let mut target = DfuTarget::new(transport, options);
+    target.ping().await?;
+    target.emit(DfuEvent::Connected);
+    let version = target.protocol_version().await?;
+    match version {
+        Some(version) => info!("Target DFU protocol version {}", version),
+        None => info!("Target does not report its DFU protocol version, skipping the version check"),
+    }
+    // the MTU only tunes the shard size, the smallest shards work with any bootloader. The MTU the
+    // link negotiated is what counts, the bootloader only reports the largest it supports.
+    let (mtu, source) = match (options.mtu, transport.mtu().await) {
+        (Some(mtu), _) => (Some(mtu), "set by the caller"),
+        (None, Some(mtu)) => (Some(mtu), "negotiated on the link"),
+        (None, None) => match target.mtu().await {
+            Err(DfuError::Protocol(e)) => {
+                warn!("MTU request failed: {}", e);
+                (None, "")
+            }
+            res => (res?, "reported by the target"),
+        },
+    };
+    let mtu = match mtu {
+        Some(mtu) if mtu > ATT_HEADER_SIZE => {
+            target.set_shard_size(mtu - ATT_HEADER_SIZE);
+            info!(
+                "Using {} byte data shards for an ATT MTU of {} bytes ({})",
+                target.shard_size, mtu, source
+            );
+            mtu
+        }
+        Some(mtu) => {
+            warn!(
+                "ignoring ATT MTU of {} bytes ({}), using the default ATT MTU {}",
+                mtu, source, DEFAULT_ATT_MTU
+            );
+            DEFAULT_ATT_MTU
+        }
+        None => {
+            info!(
+                "Using {} byte data shards for the default ATT MTU {}, neither the link nor the target reports one",
+                target.shard_size, DEFAULT_ATT_MTU
+            );
+            DEFAULT_ATT_MTU
+        }
+    };
+    let (hw, firmware) = if preflight {
+        let hw = target.hardware_version().await?;
+        match &hw {
+            Some(hw) => info!("Target {}", hw),
+            None => info!("Target does not report its hardware version, skipping hardware checks"),
+        }
+        if let (true, Some(hw)) = (check_size, &hw) {
+            DfuTarget::<T>::check_size(hw, fw_len)?;
+        }
+        let firmware = target.firmware_versions().await?;
+        if firmware.is_empty() {
+            info!("Target does not report its firmware versions");
+        }
+        for fw in &firmware {
+            info!("Target {}", fw);
+        }
+        if check_compat {
+            let init = InitPacket::parse(init_pkt)?;
+            DfuTarget::<T>::check_compat(hw.as_ref(), &firmware, &init)?;
+        }
+        (hw, firmware)
+    } else {
+        info!("Skipping the target version checks");
+        (None, Vec::new())
+    };
+    if skip_current {
+        let installed = firmware.iter().find(|fw| fw.fw_type == FirmwareType::Application);
+        let init = InitPacket::parse(init_pkt)?;
+        match (init.fw_type, init.fw_version, installed) {
+            (Some(FwType::Application), Some(packaged), Some(installed)) if installed.version >= packaged => {
+                info!(
+                    "Target already runs application version {}, the package has version {}, skipping the update",
+                    installed.version, packaged
+                );
+                return Ok(DfuSummary {
+                    bytes: 0,
+                    objects: 0,
+                    duration: Duration::ZERO,
+                    protocol_version: version,
+                    mtu,
+                    shard_size: target.shard_size,
+                    ctrl_retries: target.ctrl_retries.load(Ordering::Relaxed),
+                    crc_retries: 0,
+                    execute_retries: 0,
+                    hardware: hw,
+                    firmware,
+                    skipped: true,
+                });
+            }
+            (Some(FwType::Application), Some(_), None) if !preflight => {
+                info!("Not comparing the application version without querying the target")
+            }
+            _ => {}
+        }
+    }
     target.set_prn(0).await?;
 
-    target.create_object(Object::Command, init_pkt.len()).await?;
-    target.write_data(init_pkt).await?;
-    target.verify_crc(init_pkt.len(), crc32(init_pkt, 0)).await?;
-    target.execute().await?;
+    // the target may still hold the init packet from an interrupted transfer, re-creating the
+    // command object would discard the data transferred so far
+    let (_, cmd_offset, cmd_crc) = target.select_object(Object::Command).await?;
+    if cmd_offset == init_pkt.len() && cmd_crc == crc32(init_pkt, 0) && !force_full {
+        info!("Target already has the init packet");
+    } else {
+        target.create_object(Object::Command, init_pkt.len()).await?;
+        target.write_command(init_pkt).await?;
+    }
+    target
+        .execute_object(Object::Command, init_pkt.len(), crc32(init_pkt, 0))
+        .await?;
+    target.emit(DfuEvent::InitPacketSent);
 
-    let (max_size, offset, checksum) = target.select_object(Object::Data).await?;
-    if offset != 0 || checksum != 0 {
-        unimplemented!("DFU resumption is not supported");
+    let start = std::time::Instant::now();
+    let (max_size, mut resume_offset, resume_crc) = target.select_object(Object::Data).await?;
+    if max_size == 0 {
+        return Err(ProtocolError::InvalidResponse {
+            opcode: OpCode::ObjectSelect,
+            reason: "invalid maximum data object size",
+        }
+        .into());
+    }
+    if force_full {
+        // creating the command object resets the transfer on SDK bootloaders, the data objects recreated
+        // from the start discard what is left on others
+        if resume_offset > 0 {
+            info!("Ignoring {} bytes the target holds, starting over", resume_offset);
+        }
+        resume_offset = 0;
+    }
+    if resume_offset > fw_len {
+        return Err(ProtocolError::ResumeBeyondEnd { resume_offset, fw_len }.into());
     }
     let mut checksum: u32 = 0;
     let mut offset: usize = 0;
-    for chunk in fw_pkt.chunks(max_size) {
-        target.create_object(Object::Data, chunk.len()).await?;
-        for shard in chunk.chunks(transport.mtu().await) {
-            checksum = crc32(shard, checksum);
-            offset += shard.len();
-            target.write_data(shard).await?;
-            target.verify_crc(offset, checksum).await?;
-            // TODO add progress callback
-            println!("Uploaded {}/{} bytes", offset, fw_pkt.len());
+    let mut objects = 0;
+    let mut buf = vec![0u8; max_size];
+    if resume_offset > 0 {
+        // skip to the data object the previous transfer stopped in, or ended with
+        let mut len = read_chunk(&mut fw_pkt, &mut buf)?.len();
+        while offset + len < resume_offset {
+            if len == 0 {
+                return Err(ProtocolError::FirmwareLength {
+                    expected: fw_len,
+                    actual: offset,
+                }
+                .into());
+            }
+            checksum = crc32(&buf[..len], checksum);
+            offset += len;
+            len = read_chunk(&mut fw_pkt, &mut buf)?.len();
+        }
+        let object = &buf[..len];
+        let done = resume_offset - offset;
+        let mut resumed = false;
+        if crc32(&object[..done], checksum) == resume_crc {
+            info!("Resuming transfer at {}/{} bytes", resume_offset, fw_len);
+            // send the rest of a partially written object
+            resumed = true;
+            let (mut off, mut crc) = (resume_offset, resume_crc);
+            for shard in object[done..].chunks(target.shard_size) {
+                crc = crc32(shard, crc);
+                off += shard.len();
+                target.write_data(shard, off - shard.len()).await?;
+                target.emit(DfuEvent::ShardWritten { offset: off });
+                if target.get_crc().await? != (off, crc) {
+                    resumed = false;
+                    break;
+                }
+                target.confirm(off);
+                info!("Uploaded {}/{} bytes", off, fw_len);
+            }
+            if resumed {
+                match target.execute().await {
+                    // an object ending exactly at the resume offset may have been executed already
+                    Err(DfuError::Protocol(ProtocolError::Rejected {
+                        code: ResponseCode::OperationNotPermitted,
+                        ..
+                    })) if done == len => {}
+                    res => res?,
+                }
+                target.emit(DfuEvent::ObjectExecuted {
+                    index: offset / max_size,
+                });
+                checksum = crc;
+            }
+        }
+        if !resumed {
+            info!(
+                "Target data does not match the firmware, resending from {} bytes",
+                offset
+            );
+            checksum = target
+                .send_object(offset / max_size, object, offset, checksum, fw_len, 0)
+                .await?;
+            objects += 1;
+        }
+        offset += len;
+    }
+    // receipt notifications are only enabled now, the resumed object above is checked shard by shard
+    if prn != 0 {
+        target.set_prn(prn).await?;
+    }
+    // bootloaders with a small flash cache may refuse objects of the size they advertise, the
+    // objects then shrink and a chunk read with the previous size is sent in several of them
+    let min_size = hw
+        .as_ref()
+        .map_or(MIN_OBJECT_SIZE, |hw| hw.rom_page_size as usize)
+        .clamp(1, max_size);
+    let mut object_size = max_size;
+    let mut index = offset / max_size;
+    loop {
+        let chunk = read_chunk(&mut fw_pkt, &mut buf[..object_size])?;
+        if chunk.is_empty() {
+            break;
+        }
+        let mut sent = 0;
+        while sent < chunk.len() {
+            let object = &chunk[sent..(sent + object_size).min(chunk.len())];
+            match target.send_object(index, object, offset, checksum, fw_len, prn).await {
+                Err(DfuError::Protocol(ProtocolError::Rejected {
+                    opcode: OpCode::ObjectCreate,
+                    code: ResponseCode::InsufficientResources,
+                })) if object_size / 2 >= min_size => {
+                    object_size /= 2;
+                    warn!(
+                        "target lacks the resources for a {} byte data object, continuing with {} byte objects",
+                        object.len(),
+                        object_size
+                    );
+                    continue;
+                }
+                res => checksum = res?,
+            }
+            offset += object.len();
+            sent += object.len();
+            index += 1;
+            objects += 1;
+        }
+    }
+    if offset != fw_len {
+        return Err(ProtocolError::FirmwareLength {
+            expected: fw_len,
+            actual: offset,
+        }
+        .into());
+    }
+    // every shard was checked on its own, this catches an object that executed with less data than sent
+    let (_, actual_len, actual_crc) = target.select_object(Object::Data).await?;
+    if (actual_len, actual_crc) != (fw_len, checksum) {
+        return Err(ProtocolError::ImageMismatch {
+            expected_len: fw_len,
+            expected_crc: checksum,
+            actual_len,
+            actual_crc,
+        }
+        .into());
+    }
+    target.confirm(fw_len);
+    info!("Target holds the complete image, CRC {:#010x}", checksum);
+
+    Ok(DfuSummary {
+        bytes: fw_len - resume_offset,
+        objects,
+        duration: start.elapsed(),
+        protocol_version: version,
+        mtu,
+        shard_size: target.shard_size,
+        ctrl_retries: target.ctrl_retries.load(Ordering::Relaxed),
+        crc_retries: target.crc_retries.load(Ordering::Relaxed),
+        execute_retries: target.execute_retries.load(Ordering::Relaxed),
+        hardware: hw,
+        firmware,
+        skipped: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::MockDfuTransport;
+
+    /// Unsigned init packet of an application with fw_version 1
+    const INIT_PKT: &[u8] = &[0x0a, 0x06, 0x08, 0x01, 0x12, 0x02, 0x08, 0x01];
+
+    fn fw(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    async fn run(transport: &impl DfuTransport, fw_pkt: &[u8], options: &DfuOptions) -> Result<DfuSummary, DfuError> {
+        dfu_run_with_options(transport, INIT_PKT, fw_pkt, fw_pkt.len(), options).await
+    }
+
+    /// Target holding the init packet and the first `shards` data shards of `fw_pkt`
+    async fn interrupted(fw_pkt: &[u8], shards: usize) -> MockDfuTransport {
+        // the init packet is the first write
+        let target = MockDfuTransport::new().disconnect_at(shards + 2);
+        let err = run(&target, fw_pkt, &DfuOptions::default()).await.unwrap_err();
+        assert!(err.is_link_error(), "{}", err);
+        target
+    }
+
+    #[tokio::test]
+    async fn resume_within_object() {
+        let fw_pkt = fw(10000);
+        // 17 shards fill the first data object, 3 more end within the second
+        let target = interrupted(&fw_pkt, 20).await;
+        assert_eq!((target.data().len(), target.executed()), (4096 + 3 * 244, 4096));
+
+        let summary = run(&target, &fw_pkt, &DfuOptions::default()).await.unwrap();
+        assert_eq!(target.data(), fw_pkt);
+        assert_eq!(summary.bytes, fw_pkt.len() - (4096 + 3 * 244));
+        // neither the init packet nor the second data object were created again
+        let creates = target
+            .requests()
+            .iter()
+            .filter(|r| r[0] == OpCode::ObjectCreate as u8)
+            .count();
+        assert_eq!(creates, 3 + 1);
+    }
+
+    #[tokio::test]
+    async fn resume_at_object_end() {
+        let fw_pkt = fw(10000);
+        // the first data object is complete and executed after 17 shards
+        let target = interrupted(&fw_pkt, 17).await;
+        assert_eq!(target.executed(), 4096);
+
+        let summary = run(&target, &fw_pkt, &DfuOptions::default()).await.unwrap();
+        assert_eq!(target.data(), fw_pkt);
+        assert_eq!(summary.bytes, fw_pkt.len() - 4096);
+    }
+
+    #[tokio::test]
+    async fn resume_different_firmware() {
+        let target = interrupted(&fw(10000), 20).await;
+        // a different build resends the object the previous transfer stopped in
+        let mut fw_pkt = fw(10000);
+        fw_pkt[4500] ^= 0xff;
+        let summary = run(&target, &fw_pkt, &DfuOptions::default()).await.unwrap();
+        assert_eq!(target.data(), fw_pkt);
+        assert_eq!(summary.objects, 2);
+    }
+
+    #[tokio::test]
+    async fn resume_beyond_end() {
+        let target = interrupted(&fw(10000), 20).await;
+        let err = run(&target, &fw(4000), &DfuOptions::default()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                DfuError::Protocol(ProtocolError::ResumeBeyondEnd {
+                    resume_offset: 4828,
+                    fw_len: 4000
+                })
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn force_full_ignores_target_data() {
+        let fw_pkt = fw(10000);
+        let target = interrupted(&fw_pkt, 20).await;
+        let options = DfuOptions {
+            force_full: true,
+            ..Default::default()
+        };
+        let summary = run(&target, &fw_pkt, &options).await.unwrap();
+        assert_eq!(target.data(), fw_pkt);
+        assert_eq!((summary.bytes, summary.objects), (fw_pkt.len(), 3));
+    }
+
+    #[tokio::test]
+    async fn summary_reports_protocol_version() {
+        let summary = run(&MockDfuTransport::new(), &fw(1000), &DfuOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(summary.protocol_version, Some(1));
+        assert!(
+            summary.to_string().contains("DFU protocol version 1, MTU 247"),
+            "{}",
+            summary
+        );
+    }
+
+    /// Response with header, echoed opcode and result `code`
+    fn response(opcode: OpCode, code: ResponseCode, payload: &[u8]) -> Vec<u8> {
+        let mut response = vec![0x60, opcode.into(), code.into()];
+        response.extend_from_slice(payload);
+        response
+    }
+
+    #[test]
+    fn response_lengths() {
+        let crc = response(OpCode::CrcGet, ResponseCode::Success, &[0; 8]);
+        assert!(Response::parse(OpCode::CrcGet, &crc).is_ok());
+        for bytes in [&[][..], &[0x60], &[0x60, 0x03], &crc[..10], &[&crc[..], &[0]].concat()] {
+            let err = Response::parse(OpCode::CrcGet, bytes).unwrap_err();
+            assert!(
+                matches!(&err, ProtocolError::MalformedResponse { opcode: OpCode::CrcGet, response } if response == bytes),
+                "{:02x?}: {}",
+                bytes,
+                err
+            );
+        }
+        // failures carry no payload, whatever the opcode
+        let rejected = response(OpCode::ObjectSelect, ResponseCode::InvalidObject, &[]);
+        assert!(matches!(
+            Response::parse(OpCode::ObjectSelect, &rejected),
+            Err(ProtocolError::Rejected {
+                code: ResponseCode::InvalidObject,
+                ..
+            })
+        ));
+        let rejected = response(OpCode::ObjectSelect, ResponseCode::InvalidObject, &[0; 12]);
+        assert!(matches!(
+            Response::parse(OpCode::ObjectSelect, &rejected),
+            Err(ProtocolError::MalformedResponse { .. })
+        ));
+        // the extended error follows the result
+        let extended = response(OpCode::ObjectExecute, ResponseCode::ExtError, &[]);
+        assert!(matches!(
+            Response::parse(OpCode::ObjectExecute, &extended),
+            Err(ProtocolError::MalformedResponse { .. })
+        ));
+    }
+
+    /// Transport answering every request with the same response
+    struct Canned(Vec<u8>);
+
+    #[async_trait::async_trait]
+    impl DfuTransport for Canned {
+        async fn mtu(&self) -> Option<usize> {
+            None
+        }
+        async fn write_data(&self, _bytes: &[u8], _timeout: Duration) -> Result<(), TransportError> {
+            Ok(())
+        }
+        async fn request_ctrl(&self, _bytes: &[u8], _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            Ok(self.0.clone())
+        }
+        async fn receive_ctrl(&self, _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_responses_from_target() {
+        let select = response(OpCode::ObjectSelect, ResponseCode::Success, &[0; 12]);
+        let crc = response(OpCode::CrcGet, ResponseCode::Success, &[0; 8]);
+        for bytes in [vec![], select[..14].to_vec(), [&select[..], &[0]].concat()] {
+            let transport = Canned(bytes.clone());
+            let target = DfuTarget::new(&transport, &DfuOptions::default());
+            let err = target.select_object(Object::Data).await.unwrap_err();
+            assert!(
+                matches!(&err, DfuError::Protocol(ProtocolError::MalformedResponse { response, .. }) if *response == bytes),
+                "{:02x?}: {}",
+                bytes,
+                err
+            );
+        }
+        for bytes in [crc[..3].to_vec(), crc[..10].to_vec()] {
+            let transport = Canned(bytes);
+            let target = DfuTarget::new(&transport, &DfuOptions::default());
+            assert!(matches!(
+                target.get_crc().await,
+                Err(DfuError::Protocol(ProtocolError::MalformedResponse { .. }))
+            ));
+            assert!(matches!(
+                target.receive_crc().await,
+                Err(DfuError::Protocol(ProtocolError::MalformedResponse { .. }))
+            ));
         }
-        target.execute().await?;
     }
 
-    Ok(())
+    /// Transport that notifies the given frames, one after the other
+    struct Notifying(std::sync::Mutex<Vec<Vec<u8>>>);
+
+    #[async_trait::async_trait]
+    impl DfuTransport for Notifying {
+        async fn mtu(&self) -> Option<usize> {
+            None
+        }
+        async fn write_data(&self, _bytes: &[u8], _timeout: Duration) -> Result<(), TransportError> {
+            Ok(())
+        }
+        async fn request_ctrl(&self, _bytes: &[u8], _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            Err(TransportError::Timeout)
+        }
+        async fn receive_ctrl(&self, _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            let mut frames = self.0.lock().unwrap();
+            if frames.is_empty() {
+                return Err(TransportError::Timeout);
+            }
+            Ok(frames.remove(0))
+        }
+    }
+
+    #[tokio::test]
+    async fn receipt_after_late_responses() {
+        let mut receipt = response(OpCode::CrcGet, ResponseCode::Success, &[0; 8]);
+        receipt[3] = 0xf4;
+        let transport = Notifying(std::sync::Mutex::new(vec![
+            response(OpCode::ObjectExecute, ResponseCode::Success, &[]),
+            response(OpCode::ObjectSelect, ResponseCode::Success, &[0; 12]),
+            receipt,
+        ]));
+        let target = DfuTarget::new(&transport, &DfuOptions::default());
+        assert_eq!(target.receive_crc().await.unwrap(), (0xf4, 0));
+        assert!(matches!(
+            target.receive_crc().await,
+            Err(DfuError::Transport(TransportError::Timeout))
+        ));
+    }
+
+    #[test]
+    fn response_header() {
+        let mut crc = response(OpCode::CrcGet, ResponseCode::Success, &[0; 8]);
+        assert!(matches!(
+            Response::parse(OpCode::ObjectExecute, &crc),
+            Err(ProtocolError::UnexpectedOpcode {
+                expected: OpCode::ObjectExecute,
+                received: 0x03
+            })
+        ));
+        crc[2] = 0x42;
+        assert!(matches!(
+            Response::parse(OpCode::CrcGet, &crc),
+            Err(ProtocolError::InvalidResponse {
+                reason: "unknown response code",
+                ..
+            })
+        ));
+        crc[0] = 0x61;
+        assert!(matches!(
+            Response::parse(OpCode::CrcGet, &crc),
+            Err(ProtocolError::InvalidResponse {
+                reason: "invalid response header",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn extended_errors() {
+        let opcode = OpCode::ObjectExecute;
+        for (byte, code) in [
+            (0x05, ExtError::FwVersionFailure),
+            (0x06, ExtError::HwVersionFailure),
+            (0x0C, ExtError::VerificationFailed),
+        ] {
+            let extended = response(opcode, ResponseCode::ExtError, &[byte]);
+            let err = Response::parse(opcode, &extended).unwrap_err();
+            assert!(
+                matches!(err, ProtocolError::Extended { opcode: OpCode::ObjectExecute, code: c } if c == code),
+                "{}",
+                err
+            );
+        }
+        let extended = response(opcode, ResponseCode::ExtError, &[0xEE]);
+        assert!(Response::parse(opcode, &extended)
+            .unwrap_err()
+            .to_string()
+            .contains("0xee"));
+    }
+
+    #[test]
+    fn payload_decoders() {
+        let ok = |opcode, payload: &[u8]| response(opcode, ResponseCode::Success, payload);
+        let crc = ok(OpCode::CrcGet, &[0x10, 0x20, 0, 0, 0x78, 0x56, 0x34, 0x12]);
+        let crc: CrcResponse = Response::parse(OpCode::CrcGet, &crc).unwrap().decode().unwrap();
+        assert_eq!(
+            crc,
+            CrcResponse {
+                offset: 0x2010,
+                crc: 0x12345678
+            }
+        );
+
+        let select = ok(OpCode::ObjectSelect, &[0, 0x10, 0, 0, 0x20, 0, 0, 0, 1, 2, 3, 4]);
+        let select: SelectResponse = Response::parse(OpCode::ObjectSelect, &select)
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(
+            select,
+            SelectResponse {
+                max_size: 4096,
+                offset: 32,
+                crc: 0x04030201
+            }
+        );
+
+        let mtu = ok(OpCode::MtuGet, &[0xf7, 0x00]);
+        let mtu: MtuResponse = Response::parse(OpCode::MtuGet, &mtu).unwrap().decode().unwrap();
+        assert_eq!(mtu.mtu, 247);
+
+        let version = ok(OpCode::ProtocolVersion, &[1]);
+        let version: VersionResponse = Response::parse(OpCode::ProtocolVersion, &version)
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(version.version, 1);
+
+        let ping = ok(OpCode::Ping, &[0xa5]);
+        let ping: PingResponse = Response::parse(OpCode::Ping, &ping).unwrap().decode().unwrap();
+        assert_eq!(ping.id, 0xa5);
+
+        let words: Vec<u8> = [0x52840u32, 0x41414230, 0x100000, 0x40000, 0x1000]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let hw = ok(OpCode::HardwareVersion, &words);
+        let hw: HardwareVersion = Response::parse(OpCode::HardwareVersion, &hw).unwrap().decode().unwrap();
+        assert_eq!(
+            (hw.part, hw.rom_size, hw.ram_size, hw.rom_page_size),
+            (0x52840, 0x100000, 0x40000, 0x1000)
+        );
+
+        let mut payload = vec![0];
+        for word in [3u32, 0x27000, 0x1000] {
+            payload.extend_from_slice(&word.to_le_bytes());
+        }
+        let fw = ok(OpCode::FirmwareVersion, &payload);
+        let fw: FirmwareVersion = Response::parse(OpCode::FirmwareVersion, &fw).unwrap().decode().unwrap();
+        assert_eq!(
+            (fw.fw_type, fw.version, fw.addr, fw.len),
+            (FirmwareType::Softdevice, 3, 0x27000, 0x1000)
+        );
+        payload[0] = 0xff;
+        let fw = ok(OpCode::FirmwareVersion, &payload);
+        let fw: FirmwareVersion = Response::parse(OpCode::FirmwareVersion, &fw).unwrap().decode().unwrap();
+        assert_eq!(fw.fw_type, FirmwareType::Unknown);
+    }
+
+    #[test]
+    fn decode_checks_opcode() {
+        let crc = response(OpCode::CrcGet, ResponseCode::Success, &[0; 8]);
+        let err = Response::parse(OpCode::CrcGet, &crc)
+            .unwrap()
+            .decode::<SelectResponse>()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::UnexpectedOpcode {
+                expected: OpCode::ObjectSelect,
+                received: 0x03
+            }
+        ));
+    }
 }
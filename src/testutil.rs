@@ -0,0 +1,427 @@
+//! In-memory DFU package fixtures and target for tests
+
+use crate::package::{self, ImageKind, ImagePair, Package};
+use crate::protocol::{OpCode, ResponseCode};
+use crate::transport::{DfuTransport, TransportError};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Builder for DFU package zips, e.g.
+/// `TestPackage::new().application(bin, dat).bootloader(bin, dat).build()`
+#[derive(Default)]
+pub struct TestPackage {
+    pkg: Package,
+    omit: Vec<String>,
+    manifest: Option<String>,
+}
+
+impl TestPackage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an image, stored as `<manifest key>.bin` and `<manifest key>.dat`
+    pub fn image(mut self, kind: ImageKind, bin: &[u8], dat: &[u8]) -> Self {
+        *self.pkg.image_mut(kind) = Some(ImagePair {
+            dat_file: format!("{}.dat", kind.manifest_key()),
+            bin_file: format!("{}.bin", kind.manifest_key()),
+            init_pkt: dat.to_vec(),
+            fw_pkt: bin.to_vec(),
+        });
+        self
+    }
+
+    pub fn application(self, bin: &[u8], dat: &[u8]) -> Self {
+        self.image(ImageKind::Application, bin, dat)
+    }
+
+    pub fn bootloader(self, bin: &[u8], dat: &[u8]) -> Self {
+        self.image(ImageKind::Bootloader, bin, dat)
+    }
+
+    pub fn softdevice(self, bin: &[u8], dat: &[u8]) -> Self {
+        self.image(ImageKind::Softdevice, bin, dat)
+    }
+
+    pub fn softdevice_bootloader(self, bin: &[u8], dat: &[u8]) -> Self {
+        self.image(ImageKind::SoftdeviceBootloader, bin, dat)
+    }
+
+    /// Leave a file referenced by the manifest out of the zip, e.g. `application.bin`
+    pub fn without_file(mut self, name: &str) -> Self {
+        self.omit.push(name.to_string());
+        self
+    }
+
+    /// Replace the generated `manifest.json` with the given text
+    pub fn manifest(mut self, manifest: &str) -> Self {
+        self.manifest = Some(manifest.to_string());
+        self
+    }
+
+    /// Produce the package zip
+    pub fn build(&self) -> Vec<u8> {
+        let mut zip = Cursor::new(Vec::new());
+        package::write(&mut zip, &self.pkg).expect("writing package to memory");
+        if self.omit.is_empty() && self.manifest.is_none() {
+            return zip.into_inner();
+        }
+
+        // rewrite the archive with the requested modifications
+        let mut archive = zip::ZipArchive::new(zip).expect("reading back package");
+        let mut out = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).expect("reading back package");
+            let name = file.name().to_string();
+            if self.omit.contains(&name) {
+                continue;
+            }
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).expect("reading back package");
+            if let (Some(manifest), "manifest.json") = (&self.manifest, name.as_str()) {
+                data = manifest.as_bytes().to_vec();
+            }
+            out.start_file(name, options).expect("writing package to memory");
+            out.write_all(&data).expect("writing package to memory");
+        }
+        out.finish().expect("writing package to memory").into_inner()
+    }
+}
+
+/// In-memory bootloader emulating the object, CRC and execute semantics of the DFU control point,
+/// e.g. `dfu_run(&MockDfuTransport::new(), &init_pkt, &fw[..], fw.len())`
+///
+/// Failures are injected with the `drop_*`, `reject`, `delay_response`, `corrupt_write` and
+/// `disconnect_at` setters; shards and requests are counted from 1 over the whole run.
+pub struct MockDfuTransport {
+    state: Mutex<MockState>,
+    max_size: usize,
+    att_mtu: u16,
+}
+
+#[derive(Default)]
+struct MockState {
+    selected: u8,
+    command: Vec<u8>,
+    data: Vec<u8>,
+    /// Data length after the last executed data object
+    executed: usize,
+    object_len: usize,
+    prn: u32,
+    /// Shards since the last data object was created, for the receipt notifications
+    shards: u32,
+    writes: usize,
+    notifications: VecDeque<Vec<u8>>,
+    requests: Vec<Vec<u8>>,
+    dropped_responses: Vec<(u8, usize)>,
+    rejected_requests: Vec<(u8, ResponseCode, usize)>,
+    unsupported: Vec<OpCode>,
+    dropped_write: Option<usize>,
+    corrupted_write: Option<usize>,
+    failed_write: Option<usize>,
+    delayed_response: Option<(u8, usize, Duration)>,
+}
+
+impl Default for MockDfuTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockDfuTransport {
+    /// Target with 4 kB data objects and a 247 byte ATT MTU
+    pub fn new() -> Self {
+        MockDfuTransport {
+            state: Mutex::new(MockState::default()),
+            max_size: 4096,
+            att_mtu: 247,
+        }
+    }
+
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn att_mtu(mut self, att_mtu: u16) -> Self {
+        self.att_mtu = att_mtu;
+        self
+    }
+
+    /// Let the next `count` requests with `opcode` time out
+    pub fn drop_responses(self, opcode: OpCode, count: usize) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .dropped_responses
+            .push((opcode.into(), count));
+        self
+    }
+
+    /// Answer the next `count` requests with `opcode` with `code`, without acting on them, e.g.
+    /// OperationFailed for an execute while the bootloader is busy
+    pub fn reject(self, opcode: OpCode, code: ResponseCode, count: usize) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .rejected_requests
+            .push((opcode.into(), code, count));
+        self
+    }
+
+    /// Answer request `n` with `opcode` only after `delay`, e.g. an execute that erases flash; beyond
+    /// the timeout of the request it still takes effect, but the response is lost
+    pub fn delay_response(self, opcode: OpCode, n: usize, delay: Duration) -> Self {
+        self.state.lock().unwrap().delayed_response = Some((opcode.into(), n, delay));
+        self
+    }
+
+    /// Answer `opcode` with OpCodeNotSupported like bootloaders built with `NRF_DFU_PROTOCOL_REDUCED`;
+    /// HardwareVersion and FirmwareVersion are never supported
+    pub fn unsupported(self, opcode: OpCode) -> Self {
+        self.state.lock().unwrap().unsupported.push(opcode);
+        self
+    }
+
+    /// Lose shard `n`
+    pub fn drop_write(self, n: usize) -> Self {
+        self.state.lock().unwrap().dropped_write = Some(n);
+        self
+    }
+
+    /// Fail shard `n` with [`TransportError::Disconnected`] like a dropped link, the target keeps what
+    /// it received before for a transfer to resume
+    pub fn disconnect_at(self, n: usize) -> Self {
+        self.state.lock().unwrap().failed_write = Some(n);
+        self
+    }
+
+    /// Flip the first byte of shard `n`
+    pub fn corrupt_write(self, n: usize) -> Self {
+        self.state.lock().unwrap().corrupted_write = Some(n);
+        self
+    }
+
+    /// Init packet received
+    pub fn command(&self) -> Vec<u8> {
+        self.state.lock().unwrap().command.clone()
+    }
+
+    /// Firmware received, including a data object that was not executed yet
+    pub fn data(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    /// Length of the firmware in executed data objects
+    pub fn executed(&self) -> usize {
+        self.state.lock().unwrap().executed
+    }
+
+    /// Control point requests that were answered, in order
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    fn answer(&self, bytes: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(dropped) = state
+            .dropped_responses
+            .iter_mut()
+            .find(|(opcode, count)| Some(opcode) == bytes.first() && *count > 0)
+        {
+            dropped.1 -= 1;
+            return Err(TransportError::Timeout);
+        }
+        state.requests.push(bytes.to_vec());
+        let mut response = vec![0x60, *bytes.first().unwrap_or(&0)];
+        if let Some(rejected) = state
+            .rejected_requests
+            .iter_mut()
+            .find(|(opcode, _, count)| Some(opcode) == bytes.first() && *count > 0)
+        {
+            rejected.2 -= 1;
+            response.push(rejected.1.into());
+            return Ok(response);
+        }
+        match self.respond(&mut state, bytes) {
+            Ok(payload) => {
+                response.push(ResponseCode::Success.into());
+                response.extend_from_slice(&payload);
+            }
+            Err(code) => response.push(code.into()),
+        }
+        Ok(response)
+    }
+
+    /// Delay of the request that was just answered
+    fn delay(&self, bytes: &[u8]) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let (opcode, n, delay) = state.delayed_response?;
+        let count = state.requests.iter().filter(|r| r.first() == Some(&opcode)).count();
+        (bytes.first() == Some(&opcode) && count == n).then_some(delay)
+    }
+
+    fn respond(&self, state: &mut MockState, request: &[u8]) -> Result<Vec<u8>, ResponseCode> {
+        let opcode = OpCode::try_from(request[0]).map_err(|_| ResponseCode::OpCodeNotSupported)?;
+        if state.unsupported.contains(&opcode) {
+            return Err(ResponseCode::OpCodeNotSupported);
+        }
+        let param = |at: usize| {
+            request
+                .get(at..at + 4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .ok_or(ResponseCode::InvalidParameter)
+        };
+        let crc = |state: &MockState| {
+            let object = if state.selected == 1 {
+                &state.command
+            } else {
+                &state.data
+            };
+            let mut payload = (object.len() as u32).to_le_bytes().to_vec();
+            payload.extend_from_slice(&crc32fast::hash(object).to_le_bytes());
+            payload
+        };
+        Ok(match opcode {
+            OpCode::ProtocolVersion => vec![1],
+            OpCode::ObjectCreate => {
+                let kind = *request.get(1).ok_or(ResponseCode::InvalidParameter)?;
+                let len = param(2)? as usize;
+                state.selected = kind;
+                if kind == 1 {
+                    // a new init packet resets the transfer
+                    state.command.clear();
+                    state.data.clear();
+                    state.executed = 0;
+                } else {
+                    if len > self.max_size {
+                        return Err(ResponseCode::InsufficientResources);
+                    }
+                    // an object that was not executed is discarded
+                    state.data.truncate(state.executed);
+                    state.object_len = len;
+                    state.shards = 0;
+                }
+                vec![]
+            }
+            OpCode::ReceiptNotifSet => {
+                state.prn = param(1)?;
+                vec![]
+            }
+            OpCode::CrcGet => crc(state),
+            OpCode::ObjectExecute => {
+                if state.selected == 2 {
+                    if state.data.len() != state.executed + state.object_len {
+                        return Err(ResponseCode::OperationNotPermitted);
+                    }
+                    state.executed = state.data.len();
+                }
+                vec![]
+            }
+            OpCode::ObjectSelect => {
+                state.selected = *request.get(1).ok_or(ResponseCode::InvalidParameter)?;
+                let max_size = if state.selected == 1 { 512 } else { self.max_size };
+                let mut payload = (max_size as u32).to_le_bytes().to_vec();
+                payload.extend_from_slice(&crc(state));
+                payload
+            }
+            OpCode::MtuGet => self.att_mtu.to_le_bytes().to_vec(),
+            OpCode::Ping => vec![*request.get(1).ok_or(ResponseCode::InvalidParameter)?],
+            OpCode::Abort => vec![],
+            _ => return Err(ResponseCode::OpCodeNotSupported),
+        })
+    }
+}
+
+#[async_trait]
+impl DfuTransport for MockDfuTransport {
+    async fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    async fn write_data(&self, bytes: &[u8], _timeout: Duration) -> Result<(), TransportError> {
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+        if state.failed_write == Some(state.writes) {
+            return Err(TransportError::Disconnected);
+        }
+        if state.dropped_write == Some(state.writes) {
+            return Ok(());
+        }
+        let mut bytes = bytes.to_vec();
+        if state.corrupted_write == Some(state.writes) {
+            bytes[0] ^= 0xff;
+        }
+        // like some BLE stacks, silently truncate writes that exceed the ATT payload
+        bytes.truncate((self.att_mtu as usize).saturating_sub(3));
+        if state.selected == 1 {
+            state.command.extend_from_slice(&bytes);
+            return Ok(());
+        }
+        state.data.extend_from_slice(&bytes);
+        state.shards += 1;
+        if state.prn != 0 && state.shards.is_multiple_of(state.prn) {
+            let mut notification = vec![0x60, OpCode::CrcGet.into(), ResponseCode::Success.into()];
+            notification.extend_from_slice(&(state.data.len() as u32).to_le_bytes());
+            notification.extend_from_slice(&crc32fast::hash(&state.data).to_le_bytes());
+            state.notifications.push_back(notification);
+        }
+        Ok(())
+    }
+
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        let response = self.answer(bytes)?;
+        if let Some(delay) = self.delay(bytes) {
+            tokio::time::sleep(delay.min(timeout)).await;
+            if delay > timeout {
+                return Err(TransportError::Timeout);
+            }
+        }
+        Ok(response)
+    }
+
+    async fn receive_ctrl(&self, _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.state
+            .lock()
+            .unwrap()
+            .notifications
+            .pop_front()
+            .ok_or(TransportError::Timeout)
+    }
+}
+
+/// Serve `target` as a simulated bootloader to [`DfuTransportTcp`](crate::transport_tcp::DfuTransportTcp)
+/// connections, one after the other so that a reconnect resumes where the previous connection left
+/// off; dropped responses are not sent at all, so the client sees real timeouts
+pub async fn serve(listener: tokio::net::TcpListener, target: Arc<MockDfuTransport>) -> std::io::Result<()> {
+    use crate::transport_tcp::{read_frame, write_frame, CONTROL_POINT, DATA_POINT};
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        // a client closing the connection mid-frame is like a dropped link, not a server error
+        while let Ok(Some((channel, payload))) = read_frame(&mut stream).await {
+            let timeout = Duration::ZERO;
+            let mut frames = Vec::new();
+            match channel {
+                // delayed responses are sent late, the client decides whether it still waits for them
+                CONTROL_POINT => frames.extend(target.request_ctrl(&payload, Duration::MAX).await.ok()),
+                DATA_POINT => {
+                    let _ = target.write_data(&payload, timeout).await;
+                    while let Ok(notification) = target.receive_ctrl(timeout).await {
+                        frames.push(notification);
+                    }
+                }
+                _ => {}
+            }
+            for frame in frames {
+                if write_frame(&mut stream, CONTROL_POINT, &frame).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use async_trait::async_trait;
-use std::error::Error;
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// nRF DFU service & characteristic UUIDs
 ///
@@ -19,13 +22,263 @@ pub mod dfu_uuids {
     pub const BTTNLSS_WITH_BONDS: uuid::Uuid = uuid::Uuid::from_u128(0x8EC90004_F315_4F60_9FB8_838830DAEA50);
 }
 
+/// Errors of the link to the target
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    /// The target did not respond in time, the request may be retried
+    #[error("timed out waiting for the target")]
+    Timeout,
+    /// A write did not complete in time, which unlike a missing response is a problem of the link
+    /// rather than of the target being slow, so it is not retried
+    #[error("write to the target timed out")]
+    WriteTimeout,
+    /// No device advertising the name was found, `seen` lists the devices found instead
+    #[error("device {name} not found, devices seen: {}", if .seen.is_empty() { "none".to_string() } else { .seen.join(", ") })]
+    DeviceNotFound { name: String, seen: Vec<String> },
+    /// Several devices match the name prefix
+    #[error("several devices match {name}: {}", .candidates.join(", "))]
+    AmbiguousDevice { name: String, candidates: Vec<String> },
+    /// No Bluetooth adapter is available on the host
+    #[error("no Bluetooth adapters found (is bluetoothd running?)")]
+    NoAdapter,
+    /// The adapter selection, an index or part of the adapter's identifier, matches none or several
+    /// of the available adapters
+    #[error("Bluetooth adapter {selection:?} {reason}, available adapters: {}", .available.join(", "))]
+    AdapterSelection {
+        selection: String,
+        reason: &'static str,
+        available: Vec<String>,
+    },
+    /// The device lacks a DFU characteristic, `found` lists the services and characteristics it
+    /// offers to tell a device not in DFU mode from an incomplete discovery
+    #[error("characteristic {uuid} not found, is the device in DFU mode? It offers {}", if .found.is_empty() { "no services".to_string() } else { .found.join("; ") })]
+    CharacteristicNotFound { uuid: uuid::Uuid, found: Vec<String> },
+    /// Notifications or events stopped, i.e. the connection was lost
+    #[error("unexpected end of stream")]
+    StreamEnded,
+    /// The application rejected or did not answer the switch to the bootloader
+    #[error("buttonless DFU failed: {0}")]
+    Buttonless(String),
+    /// The target dropped the connection, it may be reconnected to
+    #[error("target disconnected")]
+    Disconnected,
+    /// The target kept the connection open after it should have reset
+    #[error("target did not disconnect")]
+    NoDisconnect,
+    /// Error reported by the underlying Bluetooth stack
+    #[error(transparent)]
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<tokio::time::error::Elapsed> for TransportError {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        TransportError::Timeout
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(e: std::io::Error) -> Self {
+        TransportError::Backend(Box::new(e))
+    }
+}
+
 /// nRF DFU transport interface
+///
+/// The protocol layer decides how long each operation may take, e.g. executing an object that
+/// writes flash takes much longer than a CRC request; exceeding `timeout` fails with
+/// [`TransportError::Timeout`]. For control point requests it only bounds waiting for the response,
+/// the write itself takes a single connection event and is bounded by the transport, failing with
+/// [`TransportError::WriteTimeout`].
+///
+/// Implemented for references and [`Arc`]s of transports too, so that a transport can be shared
+/// with a spawned task.
 #[async_trait]
 pub trait DfuTransport {
-    /// MTU of the BLE link
-    async fn mtu(&self) -> usize;
+    /// ATT MTU negotiated for the link, `None` if the platform does not report it; data point writes
+    /// are 3 bytes shorter
+    async fn mtu(&self) -> Option<usize>;
     /// Send data to data point
-    async fn write_data(&self, bytes: &[u8]) -> Result<(), Box<dyn Error>>;
-    /// Exchange request with control point
-    async fn request_ctrl(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError>;
+    /// Exchange request with control point; the response echoes the request opcode, notifications
+    /// that do not (or arrived before the request was sent) are left for `receive_ctrl`
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError>;
+    /// Wait for a control point notification that is not a response to a request,
+    /// i.e. a packet receipt notification
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError>;
+}
+
+#[async_trait]
+impl<T: DfuTransport + Sync + ?Sized> DfuTransport for &T {
+    async fn mtu(&self) -> Option<usize> {
+        (**self).mtu().await
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        (**self).write_data(bytes, timeout).await
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        (**self).request_ctrl(bytes, timeout).await
+    }
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        (**self).receive_ctrl(timeout).await
+    }
+}
+
+#[async_trait]
+impl<T: DfuTransport + Send + Sync + ?Sized> DfuTransport for Arc<T> {
+    async fn mtu(&self) -> Option<usize> {
+        (**self).mtu().await
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        (**self).write_data(bytes, timeout).await
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        (**self).request_ctrl(bytes, timeout).await
+    }
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        (**self).receive_ctrl(timeout).await
+    }
+}
+
+/// First byte of responses and notifications
+pub(crate) const RESPONSE: u8 = 0x60;
+/// Opcode of CRC responses, which packet receipt notifications share
+pub(crate) const CRC_GET: u8 = 0x03;
+
+/// Control point frames from a reader task, e.g. decoded from a serial port, told apart into
+/// responses to requests and notifications for [`DfuTransport::receive_ctrl`]; the channel closing
+/// is a disconnect
+pub(crate) struct ControlPoint {
+    frames: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<std::io::Result<Vec<u8>>>>,
+    /// Notifications received while waiting for the response to a request
+    pending: std::sync::Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl ControlPoint {
+    pub(crate) fn new(frames: tokio::sync::mpsc::UnboundedReceiver<std::io::Result<Vec<u8>>>) -> Self {
+        ControlPoint {
+            frames: tokio::sync::Mutex::new(frames),
+            pending: std::sync::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Send the request `bytes` through `send` and wait for its response, which echoes the request
+    /// opcode; anything else is left for [`ControlPoint::receive`]
+    pub(crate) async fn request(
+        &self,
+        bytes: &[u8],
+        send: impl std::future::Future<Output = Result<(), TransportError>>,
+    ) -> Result<Vec<u8>, TransportError> {
+        let mut frames = self.frames.lock().await;
+        // whatever arrived before the request was sent, e.g. a packet receipt notification in the same
+        // format as a CRC response, cannot be its response
+        while let Ok(frame) = frames.try_recv() {
+            self.pending.lock().unwrap().push_back(frame?);
+        }
+        send.await?;
+        loop {
+            let frame = frames.recv().await.ok_or(TransportError::Disconnected)??;
+            if frame.first() == Some(&RESPONSE) && frame.get(1) == bytes.first() {
+                return Ok(frame);
+            }
+            self.pending.lock().unwrap().push_back(frame);
+        }
+    }
+
+    /// Wait for the next packet receipt notification
+    pub(crate) async fn receive(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        // only packet receipt notifications are unsolicited, anything else is a late response to a
+        // request that timed out
+        let is_receipt = |value: &[u8]| value.get(..2) == Some(&[RESPONSE, CRC_GET]);
+        while let Some(value) = self.pending.lock().unwrap().pop_front() {
+            if is_receipt(&value) {
+                return Ok(value);
+            }
+            debug!("dropping unsolicited control point frame {:02x?}", value);
+        }
+        let mut frames = self.frames.lock().await;
+        let next = async {
+            loop {
+                let value = frames.recv().await.ok_or(TransportError::Disconnected)??;
+                if is_receipt(&value) {
+                    return Ok(value);
+                }
+                debug!("dropping unsolicited control point frame {:02x?}", value);
+            }
+        };
+        tokio::time::timeout(timeout, next).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXECUTE: u8 = 0x04;
+    const SELECT: u8 = 0x06;
+
+    /// Packet receipt notification, or CRC response, for `offset` bytes
+    fn receipt(offset: u32) -> Vec<u8> {
+        let mut frame = vec![RESPONSE, CRC_GET, 0x01];
+        frame.extend_from_slice(&offset.to_le_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    #[tokio::test]
+    async fn response_among_other_frames() {
+        let (target, frames) = tokio::sync::mpsc::unbounded_channel();
+        let ctrl = ControlPoint::new(frames);
+        let send = async {
+            for frame in [
+                receipt(244),
+                // late response to an earlier request
+                [&[RESPONSE, SELECT, 0x01][..], &[0; 12]].concat(),
+                // not a response at all
+                vec![0x20, EXECUTE],
+                vec![RESPONSE, EXECUTE, 0x01],
+            ] {
+                target.send(Ok(frame)).unwrap();
+            }
+            Ok(())
+        };
+        let response = ctrl.request(&[EXECUTE], send).await.unwrap();
+        assert_eq!(response, [RESPONSE, EXECUTE, 0x01]);
+        // only the receipt notification is left for the protocol
+        let timeout = Duration::from_millis(50);
+        assert_eq!(ctrl.receive(timeout).await.unwrap(), receipt(244));
+        assert!(matches!(ctrl.receive(timeout).await, Err(TransportError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn receipt_is_not_a_crc_response() {
+        let (target, frames) = tokio::sync::mpsc::unbounded_channel();
+        let ctrl = ControlPoint::new(frames);
+        // received before the CRC is requested
+        target.send(Ok(receipt(100))).unwrap();
+        let send = async {
+            target.send(Ok(receipt(200))).unwrap();
+            Ok(())
+        };
+        assert_eq!(ctrl.request(&[CRC_GET], send).await.unwrap(), receipt(200));
+        assert_eq!(ctrl.receive(Duration::from_millis(50)).await.unwrap(), receipt(100));
+    }
+
+    #[tokio::test]
+    async fn closed_channel_is_a_disconnect() {
+        let (target, frames) = tokio::sync::mpsc::unbounded_channel();
+        let ctrl = ControlPoint::new(frames);
+        target.send(Ok(receipt(100))).unwrap();
+        drop(target);
+        let send = async { Ok(()) };
+        assert!(matches!(
+            ctrl.request(&[EXECUTE], send).await,
+            Err(TransportError::Disconnected)
+        ));
+        // what arrived before is still delivered
+        assert_eq!(ctrl.receive(Duration::from_millis(50)).await.unwrap(), receipt(100));
+        assert!(matches!(
+            ctrl.receive(Duration::from_millis(50)).await,
+            Err(TransportError::Disconnected)
+        ));
+    }
 }
@@ -1,117 +1,1589 @@
 use crate::transport::dfu_uuids::*;
-use crate::transport::DfuTransport;
+use crate::transport::{DfuTransport, TransportError, CRC_GET, RESPONSE};
 
 use async_trait::async_trait;
-use btleplug::api::{Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType};
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, PeripheralProperties, ScanFilter,
+    ValueNotification, WriteType,
+};
 use btleplug::platform::Adapter;
-use btleplug::platform::Peripheral;
-use futures::stream::StreamExt;
-use std::error::Error;
+use btleplug::platform::{Peripheral, PeripheralId};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use log::{debug, info, trace, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+impl From<btleplug::Error> for TransportError {
+    fn from(e: btleplug::Error) -> Self {
+        TransportError::Backend(Box::new(e))
+    }
+}
 
 async fn find_characteristic_by_uuid(
     peripheral: &Peripheral,
     uuid: uuid::Uuid,
-) -> Result<Characteristic, Box<dyn Error>> {
+) -> Result<Characteristic, TransportError> {
     for char in peripheral.characteristics() {
         if uuid == char.uuid {
             return Ok(char);
         }
     }
-    Err("characteristic not found".into())
+    Err(TransportError::CharacteristicNotFound {
+        uuid,
+        found: gatt_table(peripheral),
+    })
 }
 
-async fn find_peripheral_by_name(central: &Adapter, name: &str) -> Result<Peripheral, Box<dyn Error>> {
-    println!("Searching for {} ...", name);
-    central.start_scan(ScanFilter::default()).await?;
+/// Services of `peripheral` with their characteristics, for errors
+fn gatt_table(peripheral: &Peripheral) -> Vec<String> {
+    peripheral
+        .services()
+        .iter()
+        .map(|service| {
+            let characteristics: Vec<_> = service.characteristics.iter().map(|chr| chr.uuid.to_string()).collect();
+            format!(
+                "service {} with characteristics [{}]",
+                service.uuid,
+                characteristics.join(", ")
+            )
+        })
+        .collect()
+}
+
+/// DFU characteristics of a connected device
+enum DfuCharacteristics {
+    /// An application offering buttonless DFU, `bonded` for the variant that needs an encrypted link
+    Buttonless { buttonless: Characteristic, bonded: bool },
+    /// The bootloader in DFU mode
+    Bootloader {
+        control_point: Characteristic,
+        data_point: Characteristic,
+    },
+}
+
+/// DFU characteristics of `peripheral`: the bootloader's if it is in DFU mode already, otherwise a
+/// buttonless DFU trigger (unless [`ConnectOptions::no_buttonless`]), the bonded variant preferred
+async fn find_dfu_characteristics(
+    peripheral: &Peripheral,
+    options: &ConnectOptions,
+) -> Result<DfuCharacteristics, TransportError> {
+    let control_point = find_characteristic_by_uuid(peripheral, CTRL_PT).await;
+    let data_point = find_characteristic_by_uuid(peripheral, DATA_PT).await;
+    let e = match (control_point, data_point) {
+        (Ok(control_point), Ok(data_point)) => {
+            return Ok(DfuCharacteristics::Bootloader {
+                control_point,
+                data_point,
+            })
+        }
+        (Err(e), _) | (_, Err(e)) => e,
+    };
+    let buttonless = if options.no_buttonless {
+        &[][..]
+    } else {
+        &[(BTTNLSS_WITH_BONDS, true), (BTTNLSS, false)][..]
+    };
+    for &(uuid, bonded) in buttonless {
+        if let Ok(buttonless) = find_characteristic_by_uuid(peripheral, uuid).await {
+            return Ok(DfuCharacteristics::Buttonless { buttonless, bonded });
+        }
+    }
+    Err(e)
+}
+
+/// Times services are discovered when a DFU characteristic is missing, see
+/// [`discover_dfu_characteristics`]
+const DISCOVERY_ATTEMPTS: u32 = 3;
+/// Delay between discovering services again
+const DISCOVERY_DELAY: Duration = Duration::from_millis(250);
+
+/// Discover the services of the connected `peripheral` and look up its DFU characteristics; some
+/// platforms (Windows) report no characteristics right after connecting, so discovery is repeated up
+/// to [`DISCOVERY_ATTEMPTS`] times while one is missing
+async fn discover_dfu_characteristics(
+    peripheral: &Peripheral,
+    options: &ConnectOptions,
+) -> Result<DfuCharacteristics, TransportError> {
+    let mut attempt = 1;
+    loop {
+        peripheral.discover_services().await?;
+        match find_dfu_characteristics(peripheral, options).await {
+            Err(TransportError::CharacteristicNotFound { uuid, found }) if attempt < DISCOVERY_ATTEMPTS => {
+                debug!(
+                    "characteristic {} not found among {} services, discovering again",
+                    uuid,
+                    found.len()
+                );
+                tokio::time::sleep(DISCOVERY_DELAY).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Connect to `peripheral` and look up its DFU characteristics, making up to
+/// [`ConnectOptions::connect_attempts`] attempts as the first connection after a scan often fails;
+/// fails with the error of the last one
+async fn connect_peripheral(
+    peripheral: &Peripheral,
+    options: &ConnectOptions,
+) -> Result<DfuCharacteristics, TransportError> {
+    let attempts = options.connect_attempts.max(1);
+    let mut backoff = options.connect_backoff;
+    let mut attempt = 1;
+    loop {
+        let res = async {
+            peripheral.connect().await?;
+            discover_dfu_characteristics(peripheral, options).await
+        }
+        .await;
+        match res {
+            Ok(characteristics) => return Ok(characteristics),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => {
+                warn!(
+                    "connect attempt {}/{} failed: {}, retrying in {:?}",
+                    attempt, attempts, e, backoff
+                );
+                let _ = tokio::time::timeout(options.write_timeout, peripheral.disconnect()).await;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Scan filter for devices advertising the DFU service, the controller filtering the advertisements
+/// matters with many devices around
+fn dfu_filter() -> ScanFilter {
+    ScanFilter {
+        services: vec![SERVICE],
+    }
+}
+
+/// Whether the device advertises the DFU service, as service UUID or service data, which the
+/// bootloader and buttonless applications do
+fn advertises_dfu(properties: &PeripheralProperties) -> bool {
+    properties.services.contains(&SERVICE) || properties.service_data.contains_key(&SERVICE)
+}
+
+/// Time a scan for devices advertising the DFU service gets before scanning for any device, as the
+/// application of a buttonless target may not advertise it
+const DFU_SCAN: Duration = Duration::from_secs(3);
+
+async fn find_peripheral_by_name(
+    central: &Adapter,
+    name: &str,
+    options: &ConnectOptions,
+) -> Result<Peripheral, TransportError> {
+    if let Some(spec) = &options.id {
+        return find_peripheral(central, &format!("[{}]", spec), options, Choice::First, |found, _| {
+            id_matches(spec, &found.to_string())
+        })
+        .await;
+    }
+    find_peripheral(central, name, options, Choice::of(name, options), |_, properties| {
+        properties_match(name, options, properties)
+    })
+    .await
+}
+
+/// Whether a device advertising `properties` is the target named `name`, at the address and with
+/// the signal strength the `options` require
+fn properties_match(name: &str, options: &ConnectOptions, properties: &PeripheralProperties) -> bool {
+    // without a signal strength a device cannot be told to be close enough
+    let close = options
+        .min_rssi
+        .is_none_or(|min| properties.rssi.is_some_and(|rssi| rssi >= min));
+    // platforms hiding addresses (macOS) report them all as zero
+    let at = options
+        .address
+        .is_none_or(|address| properties.address == BDAddr::default() || properties.address == address);
+    close
+        && at
+        && properties
+            .local_name
+            .as_deref()
+            .is_some_and(|local_name| options.name_matches(name, local_name))
+}
+
+/// Whether `id` is the platform identifier `spec`, e.g. a CoreBluetooth UUID on macOS, an address on
+/// Windows or `hci0/dev_C0_98_E5_49_00_01` on Linux; case and separators do not matter, nor does
+/// the adapter unless `spec` includes it
+fn id_matches(spec: &str, id: &str) -> bool {
+    let normalize = |id: &str| {
+        let id = id.trim().trim_start_matches("/org/bluez/").to_ascii_lowercase();
+        id.replace("dev_", "").replace(['-', ':', '_'], "")
+    };
+    let (spec, id) = (normalize(spec), normalize(id));
+    if spec.contains('/') {
+        spec == id
+    } else {
+        id.rsplit('/').next() == Some(spec.as_str())
+    }
+}
+
+/// Which device [`find_peripheral`] picks if several match
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Choice {
+    /// The first one found
+    First,
+    /// None, it fails
+    Unique,
+    /// The one with the strongest signal
+    Strongest,
+}
+
+impl Choice {
+    /// How a device named `name` is picked
+    fn of(name: &str, options: &ConnectOptions) -> Self {
+        if options.min_rssi.is_some() {
+            Choice::Strongest
+        } else if name.ends_with('*') && !options.first {
+            // a prefix may match several devices
+            Choice::Unique
+        } else {
+            Choice::First
+        }
+    }
+}
+
+/// Index of the strongest of the signal strengths `rssi`, the first of equally strong ones; a
+/// missing one is weaker than any
+fn strongest(rssi: impl IntoIterator<Item = Option<i16>>) -> usize {
+    let mut strongest = (0, None);
+    for (index, rssi) in rssi.into_iter().enumerate() {
+        if rssi > strongest.1 {
+            strongest = (index, rssi);
+        }
+    }
+    strongest.0
+}
+
+/// Time the scan goes on after the first match for another device matching, see
+/// [`find_peripheral`]
+const CANDIDATE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Scan for a device that `matches`, described as `target` in the log and errors, unless `choice`
+/// is [`Choice::First`] also for others matching
+async fn find_peripheral(
+    central: &Adapter,
+    target: &str,
+    options: &ConnectOptions,
+    choice: Choice,
+    matches: impl Fn(&PeripheralId, &PeripheralProperties) -> bool,
+) -> Result<Peripheral, TransportError> {
+    info!("Searching for {} ...", target);
+    let deadline = tokio::time::Instant::now() + options.scan_timeout;
+    // devices seen by ID, for the error if the target is not among them
+    let mut seen = BTreeMap::new();
     let mut events = central.events().await?;
+    let mut found = Vec::new();
+    for dfu_only in [true, false] {
+        let (filter, until) = if dfu_only {
+            (dfu_filter(), deadline.min(tokio::time::Instant::now() + DFU_SCAN))
+        } else {
+            info!("No {} with the DFU service found, searching all devices ...", target);
+            (ScanFilter::default(), deadline)
+        };
+        central.start_scan(filter).await?;
+        let res = async {
+            if let Ok(peripheral) =
+                tokio::time::timeout_at(until, discover(central, &mut events, &matches, dfu_only, &mut seen)).await
+            {
+                found.push(peripheral?);
+            }
+            while choice != Choice::First && !found.is_empty() {
+                let ids: Vec<PeripheralId> = found.iter().map(Peripheral::id).collect();
+                let other =
+                    |id: &PeripheralId, properties: &PeripheralProperties| !ids.contains(id) && matches(id, properties);
+                match tokio::time::timeout(
+                    CANDIDATE_WINDOW,
+                    discover(central, &mut events, &other, dfu_only, &mut seen),
+                )
+                .await
+                {
+                    Ok(peripheral) => found.push(peripheral?),
+                    Err(_) => break,
+                }
+            }
+            Ok::<_, TransportError>(())
+        }
+        .await;
+        central.stop_scan().await?;
+        res?;
+        if !found.is_empty() {
+            break;
+        }
+    }
+    let describe = |id: String| match seen.get(&id) {
+        Some(Some(name)) => format!("{} [{}]", name, id),
+        _ => format!("[{}]", id),
+    };
+    match found.len() {
+        0 => {
+            let unnamed = seen.values().filter(|name| name.is_none()).count();
+            let mut seen: Vec<String> = seen
+                .iter()
+                .filter_map(|(id, name)| Some(format!("{} [{}]", name.as_ref()?, id)))
+                .collect();
+            if unnamed > 0 {
+                seen.push(format!("{} without a name", unnamed));
+            }
+            Err(TransportError::DeviceNotFound {
+                name: target.to_string(),
+                seen,
+            })
+        }
+        1 => Ok(found.remove(0)),
+        _ if choice == Choice::Strongest => {
+            let mut rssi = Vec::new();
+            for peripheral in &found {
+                let strength = peripheral.properties().await?.and_then(|properties| properties.rssi);
+                info!(
+                    "Candidate {} with RSSI {:?}",
+                    describe(peripheral.id().to_string()),
+                    strength
+                );
+                rssi.push(strength);
+            }
+            Ok(found.swap_remove(strongest(rssi)))
+        }
+        _ => Err(TransportError::AmbiguousDevice {
+            name: target.to_string(),
+            candidates: found
+                .iter()
+                .map(|peripheral| describe(peripheral.id().to_string()))
+                .collect(),
+        }),
+    }
+}
+
+/// Wait for a device that `matches`, with `dfu_only` that also advertises the DFU service (as service
+/// UUID or service data) in case the platform does not filter the scan; records the name of every
+/// device in `seen`
+async fn discover(
+    central: &Adapter,
+    events: &mut Pin<Box<dyn Stream<Item = CentralEvent> + Send>>,
+    matches: &impl Fn(&PeripheralId, &PeripheralProperties) -> bool,
+    dfu_only: bool,
+    seen: &mut BTreeMap<String, Option<String>>,
+) -> Result<Peripheral, TransportError> {
+    let accept = |peripheral: &Peripheral, properties: PeripheralProperties, seen: &mut BTreeMap<_, _>| {
+        let id = peripheral.id();
+        let known: &mut Option<String> = seen.entry(id.to_string()).or_default();
+        if known.is_none() {
+            *known = properties.local_name.clone();
+        }
+        if !matches(&id, &properties) {
+            return false;
+        }
+        if dfu_only && !advertises_dfu(&properties) {
+            return false;
+        }
+        // as advertised, to show what matched
+        info!(
+            "Found [{}] at [{}]",
+            properties.local_name.unwrap_or_default().escape_debug(),
+            id
+        );
+        true
+    };
+    // a device cached by an earlier scan may only be reported as updated, or not at all until it
+    // changes; one with a signal strength was heard during this scan
+    for peripheral in central.peripherals().await? {
+        if let Some(properties) = peripheral.properties().await? {
+            if properties.rssi.is_some() && accept(&peripheral, properties, seen) {
+                return Ok(peripheral);
+            }
+        }
+    }
     while let Some(event) = events.next().await {
-        if let CentralEvent::DeviceDiscovered(id) = event {
-            let local_name = central.peripheral(&id).await?.properties().await?.unwrap().local_name;
-            if let Some(n) = local_name {
-                println!("Found [{}] at [{}]", n, id);
-                if n == name {
-                    central.stop_scan().await?;
-                    return Ok(central.peripheral(&id).await?);
+        let id = match event {
+            CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => id,
+            _ => continue,
+        };
+        let peripheral = central.peripheral(&id).await?;
+        let Some(properties) = peripheral.properties().await? else {
+            continue;
+        };
+        if accept(&peripheral, properties, seen) {
+            return Ok(peripheral);
+        }
+    }
+    Err(TransportError::StreamEnded)
+}
+
+/// Bluetooth adapter by its index or a part of its identifier, e.g. `hci1` or the address on Linux;
+/// the first one without a `selection`
+async fn select_adapter(selection: Option<&str>) -> Result<Adapter, TransportError> {
+    let mut adapters = select_adapters(selection).await?;
+    if adapters.len() > 1 {
+        let available: Vec<&str> = adapters.iter().map(|(info, _)| info.as_str()).collect();
+        info!("Using Bluetooth adapter {}", available.join(", "));
+    }
+    Ok(adapters.swap_remove(0).1)
+}
+
+/// The Bluetooth adapter matching `selection` (see [`select_adapter`]), all of them without one,
+/// with their index and identifier
+async fn select_adapters(selection: Option<&str>) -> Result<Vec<(String, Adapter)>, TransportError> {
+    let manager = btleplug::platform::Manager::new().await?;
+    let mut adapters = Vec::new();
+    for (index, adapter) in manager.adapters().await?.into_iter().enumerate() {
+        let info = adapter.adapter_info().await?;
+        adapters.push((format!("{}: {}", index, info), info, adapter));
+    }
+    let index = match selection {
+        _ if adapters.is_empty() => return Err(TransportError::NoAdapter),
+        None => {
+            return Ok(adapters
+                .into_iter()
+                .map(|(label, _, adapter)| (label, adapter))
+                .collect())
+        }
+        Some(selection) => {
+            let matching: Vec<usize> = match selection.parse::<usize>() {
+                Ok(index) if index < adapters.len() => vec![index],
+                _ => (0..adapters.len())
+                    .filter(|&index| adapters[index].1.contains(selection))
+                    .collect(),
+            };
+            match matching[..] {
+                [index] => index,
+                _ => {
+                    return Err(TransportError::AdapterSelection {
+                        selection: selection.to_string(),
+                        reason: if matching.is_empty() {
+                            "not found"
+                        } else {
+                            "is ambiguous"
+                        },
+                        available: adapters.into_iter().map(|(label, _, _)| label).collect(),
+                    })
+                }
+            }
+        }
+    };
+    let (label, _, adapter) = adapters.swap_remove(index);
+    Ok(vec![(label, adapter)])
+}
+
+/// Search for the device advertising `name` on all `adapters` at once, as it may be in range of
+/// only one of them; returns the adapter that found it too
+async fn find_on_adapters(
+    adapters: Vec<(String, Adapter)>,
+    name: &str,
+    options: &ConnectOptions,
+) -> Result<(Adapter, Peripheral), TransportError> {
+    let several = adapters.len() > 1;
+    let centrals = adapters.clone();
+    let mut searches: FuturesUnordered<_> = adapters
+        .into_iter()
+        .map(|(label, central)| async move {
+            let res = find_peripheral_by_name(&central, name, options).await;
+            (label, central, res)
+        })
+        .collect();
+    let mut errors = Vec::new();
+    while let Some((label, central, res)) = searches.next().await {
+        match res {
+            Ok(peripheral) => {
+                drop(searches);
+                // the searches dropped may have left their adapters scanning
+                for (_, other) in centrals.iter().filter(|(other, _)| *other != label) {
+                    let _ = other.stop_scan().await;
+                }
+                if several {
+                    info!("Using Bluetooth adapter {}", label);
+                }
+                return Ok((central, peripheral));
+            }
+            Err(e) => {
+                if several {
+                    warn!("searching on Bluetooth adapter {} failed: {}", label, e);
+                }
+                errors.push(e);
+            }
+        }
+    }
+    // none found the device, the devices seen by any of them help best
+    let mut seen = Vec::new();
+    for e in errors.iter_mut() {
+        match e {
+            TransportError::DeviceNotFound { seen: more, .. } => seen.append(more),
+            _ => return Err(errors.swap_remove(0)),
+        }
+    }
+    seen.sort();
+    seen.dedup();
+    Err(TransportError::DeviceNotFound {
+        name: name.to_string(),
+        seen,
+    })
+}
+
+/// The device at `address` if one of the adapters already knows it, so that it can be connected to
+/// without scanning
+async fn find_known(adapters: &[(String, Adapter)], address: BDAddr) -> Option<(Adapter, Peripheral)> {
+    for (label, central) in adapters {
+        let Ok(peripherals) = central.peripherals().await else {
+            continue;
+        };
+        if let Some(peripheral) = peripherals
+            .into_iter()
+            .find(|peripheral| peripheral.address() == address)
+        {
+            debug!("Bluetooth adapter {} knows {} as [{}]", label, address, peripheral.id());
+            return Some((central.clone(), peripheral));
+        }
+    }
+    None
+}
+
+/// Scan for up to `within` for a device advertising one of `names` (see
+/// [`ConnectOptions::name_matches`]), without connecting to it.
+/// Returns the name seen first, `None` if none showed up in time.
+pub async fn scan_for(
+    names: &[&str],
+    options: &ConnectOptions,
+    within: Duration,
+) -> Result<Option<String>, TransportError> {
+    let central = select_adapter(options.adapter.as_deref()).await?;
+    info!("Searching for {} ...", names.join(" or "));
+    central.start_scan(ScanFilter::default()).await?;
+    let mut events = central.events().await?;
+    let found = async {
+        while let Some(event) = events.next().await {
+            if let CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) = event {
+                let properties = central.peripheral(&id).await?.properties().await?;
+                if let Some(n) = properties.and_then(|p| p.local_name) {
+                    if names.iter().any(|name| options.name_matches(name, &n)) {
+                        info!("Found [{}] at [{}]", n.escape_debug(), id);
+                        return Ok(Some(n));
+                    }
+                }
+            }
+        }
+        Err(TransportError::StreamEnded)
+    };
+    let res = tokio::time::timeout(within, found).await.unwrap_or(Ok(None));
+    central.stop_scan().await?;
+    res
+}
+
+/// Wait up to `within` until `peripheral` drops the connection
+async fn wait_disconnect(central: &Adapter, peripheral: &Peripheral, within: Duration) -> Result<(), TransportError> {
+    let mut events = central.events().await?;
+    if !peripheral.is_connected().await? {
+        return Ok(());
+    }
+    let id = peripheral.id();
+    let disconnected = async {
+        while let Some(event) = events.next().await {
+            if let CentralEvent::DeviceDisconnected(ev_id) = event {
+                if ev_id == id {
+                    return Ok(());
+                }
+            }
+        }
+        Err(TransportError::StreamEnded)
+    };
+    tokio::time::timeout(within, disconnected)
+        .await
+        .map_err(|_| TransportError::NoDisconnect)?
+}
+
+/// Address the bootloader advertises at after the buttonless switch, the application's with the
+/// least significant byte incremented
+fn bootloader_address(application: BDAddr) -> BDAddr {
+    let mut address = application.into_inner();
+    address[5] = address[5].wrapping_add(1);
+    address.into()
+}
+
+/// Request of the buttonless DFU characteristic to reset into the bootloader
+const BTTNLSS_ENTER: u8 = 0x01;
+/// Request of the buttonless DFU characteristic to set the name the bootloader advertises
+const BTTNLSS_SET_NAME: u8 = 0x02;
+/// Longest name the bootloader can be told to advertise, in bytes
+pub const ADV_NAME_MAX: usize = 20;
+/// First byte of buttonless DFU responses
+const BTTNLSS_RESPONSE: u8 = 0x20;
+
+/// Check that the bootloader can be told to advertise `name`, see [`ConnectOptions::dfu_adv_name`]
+pub fn check_adv_name(name: &str) -> Result<(), String> {
+    match name.len() {
+        0 => Err("the advertisement name must not be empty".to_string()),
+        len if len > ADV_NAME_MAX => Err(format!(
+            "the advertisement name {:?} is {} bytes long, the bootloader takes at most {}",
+            name, len, ADV_NAME_MAX
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Send `request` to the buttonless DFU characteristic `buttonless` and check the indication
+/// answering it, received from `notifications`
+async fn buttonless_request(
+    peripheral: &Peripheral,
+    buttonless: &Characteristic,
+    notifications: &mut Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    request: &[u8],
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    let opcode = request[0];
+    tokio::time::timeout(
+        options.write_timeout,
+        peripheral.write(buttonless, request, WriteType::WithResponse),
+    )
+    .await
+    .map_err(|_| TransportError::WriteTimeout)??;
+    let response = tokio::time::timeout(options.notify_timeout, async {
+        while let Some(ntf) = notifications.next().await {
+            if ntf.uuid != buttonless.uuid {
+                continue;
+            }
+            if ntf.value.get(..2) == Some(&[BTTNLSS_RESPONSE, opcode]) {
+                return Ok(ntf.value);
+            }
+            debug!("ignoring buttonless DFU indication {:02x?}", ntf.value);
+        }
+        Err(TransportError::Buttonless(
+            "the device disconnected without acknowledging the request".to_string(),
+        ))
+    })
+    .await
+    .map_err(|_| {
+        TransportError::Buttonless(format!("request not acknowledged within {:?}", options.notify_timeout))
+    })??;
+    match response[..] {
+        [BTTNLSS_RESPONSE, _, 0x01] => Ok(()),
+        [BTTNLSS_RESPONSE, _, code] => Err(TransportError::Buttonless(match code {
+            0x02 => "request not supported".to_string(),
+            0x04 => "operation failed".to_string(),
+            0x05 => "advertisement name rejected".to_string(),
+            0x06 => "busy".to_string(),
+            0x07 => "not bonded, pair the device first".to_string(),
+            _ => format!("error code {:#04x}", code),
+        })),
+        _ => Err(TransportError::Buttonless(format!(
+            "unexpected response {:02x?}",
+            response
+        ))),
+    }
+}
+
+/// Ask the application to reset into the bootloader through the buttonless DFU characteristic
+/// `buttonless`, `bonded` for the variant that needs an encrypted link, after setting the name the
+/// bootloader advertises to [`ConnectOptions::dfu_adv_name`]
+async fn enter_bootloader(
+    peripheral: &Peripheral,
+    buttonless: &Characteristic,
+    bonded: bool,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    info!(
+        "Switching to the bootloader{} ...",
+        if bonded { " (bonded)" } else { "" }
+    );
+    // listening before subscribing, so that not even an indication sent right away is missed
+    let mut notifications = peripheral.notifications().await?;
+    // the bonded variant only accepts indications over an encrypted link, the OS pairs when
+    // enabling them needs it
+    peripheral.subscribe(buttonless).await.map_err(|e| {
+        if bonded {
+            TransportError::Buttonless(format!("enabling indications failed, is the device paired? ({})", e))
+        } else {
+            e.into()
+        }
+    })?;
+    match &options.dfu_adv_name {
+        // the bonded bootloader keeps the address, and does not take a name
+        Some(_) if bonded => warn!("the bonded buttonless service does not set the advertisement name"),
+        Some(name) => {
+            check_adv_name(name).map_err(TransportError::Buttonless)?;
+            info!("Setting the bootloader's advertisement name to {} ...", name);
+            let mut request = vec![BTTNLSS_SET_NAME, name.len() as u8];
+            request.extend_from_slice(name.as_bytes());
+            buttonless_request(peripheral, buttonless, &mut notifications, &request, options)
+                .await
+                .map_err(|e| match e {
+                    TransportError::Buttonless(e) => {
+                        TransportError::Buttonless(format!("setting the advertisement name failed: {}", e))
+                    }
+                    e => e,
+                })?;
+        }
+        None => {}
+    }
+    buttonless_request(peripheral, buttonless, &mut notifications, &[BTTNLSS_ENTER], options).await
+}
+
+/// A device found by [`scan`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanResult {
+    /// Advertised name
+    pub name: Option<String>,
+    /// Platform identifier, which is all there is to tell devices apart on macOS
+    pub id: String,
+    /// Bluetooth address, all zeros where the platform hides it
+    pub address: String,
+    /// Signal strength in dBm
+    pub rssi: Option<i16>,
+    /// Whether the device advertises the DFU service, i.e. is a bootloader or a buttonless
+    /// application
+    pub dfu: bool,
+}
+
+/// Scan for `within` and list the devices found, strongest first; with `dfu_only` only those
+/// advertising the DFU service, and not those weaker than [`ConnectOptions::min_rssi`]
+pub async fn scan(
+    options: &ConnectOptions,
+    within: Duration,
+    dfu_only: bool,
+) -> Result<Vec<ScanResult>, TransportError> {
+    let central = select_adapter(options.adapter.as_deref()).await?;
+    info!("Scanning for {:?} ...", within);
+    let mut events = central.events().await?;
+    central
+        .start_scan(if dfu_only { dfu_filter() } else { ScanFilter::default() })
+        .await?;
+    // only the devices heard now, the platform also knows those cached by earlier scans
+    let mut heard = Vec::new();
+    let _ = tokio::time::timeout(within, async {
+        while let Some(event) = events.next().await {
+            if let CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) = event {
+                if !heard.contains(&id) {
+                    heard.push(id);
                 }
             }
         }
+    })
+    .await;
+    central.stop_scan().await?;
+    let mut found = Vec::new();
+    for id in heard {
+        let peripheral = central.peripheral(&id).await?;
+        let Some(properties) = peripheral.properties().await? else {
+            continue;
+        };
+        // without a signal strength it cannot be told to be close
+        if options.min_rssi.is_some_and(|min| properties.rssi < Some(min)) {
+            continue;
+        }
+        if dfu_only && !advertises_dfu(&properties) {
+            continue;
+        }
+        found.push(ScanResult {
+            dfu: advertises_dfu(&properties),
+            name: properties.local_name,
+            id: peripheral.id().to_string(),
+            address: properties.address.to_string(),
+            rssi: properties.rssi,
+        });
+    }
+    found.sort_by_key(|device| std::cmp::Reverse(device.rssi));
+    Ok(found)
+}
+
+/// Opcode of creating an object
+const OBJECT_CREATE: u8 = 0x01;
+/// Opcode of executing an object
+const OBJECT_EXECUTE: u8 = 0x04;
+
+/// How to find and connect to the target
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Bluetooth adapter by its index or a part of its identifier, e.g. `hci1` or the address on
+    /// Linux; by default the first one
+    pub adapter: Option<String>,
+    /// With a name prefix, connect to the first device matching rather than failing if several do
+    pub first: bool,
+    /// Compare names ignoring case and surrounding whitespace or NUL padding
+    pub ignore_case: bool,
+    /// How data point writes are sent
+    pub data_write_mode: DataWriteMode,
+    /// ATT MTU the link negotiated, which btleplug cannot tell; longer data writes are split
+    pub mtu: Option<usize>,
+    /// Ignore devices with a weaker signal (in dBm) or none reported, and pick the strongest of
+    /// several matching
+    pub min_rssi: Option<i16>,
+    /// Address of the target, connected to directly if an adapter already knows the device (BlueZ
+    /// keeps the devices it saw or paired with), and otherwise required of the device found by name
+    /// where the platform exposes addresses
+    pub address: Option<BDAddr>,
+    /// Platform identifier of the target, as [`scan`] lists it, to find it by instead of its name;
+    /// the stable identifier on macOS, which does not expose addresses
+    pub id: Option<String>,
+    /// Name the bootloader advertises after the buttonless switch, at most [`ADV_NAME_MAX`] bytes,
+    /// so that it is found by that name rather than by the next address or the DFU service
+    pub dfu_adv_name: Option<String>,
+    /// Ignore the buttonless DFU characteristic and expect the device in the bootloader already, for
+    /// applications with an unrelated characteristic of that UUID
+    pub no_buttonless: bool,
+    /// How long to scan for the target before giving up
+    pub scan_timeout: Duration,
+    /// Time the bootloader gets to start advertising after the buttonless switch
+    pub settle_delay: Duration,
+    /// Attempts of [`DfuTransportBtleplug::reconnect`] before it gives up
+    pub reconnect_attempts: u32,
+    /// Delay before the second reconnect attempt, doubling for each further one
+    pub reconnect_backoff: Duration,
+    /// Attempts to connect to a device found and discover its services before giving up
+    pub connect_attempts: u32,
+    /// Delay before the second connect attempt, doubling for each further one
+    pub connect_backoff: Duration,
+    /// Timeout of the writes and (un)subscriptions of connecting and disconnecting, and of control
+    /// point writes; the responses get theirs from the protocol
+    pub write_timeout: Duration,
+    /// Timeout of waiting for a notification while connecting, i.e. the buttonless DFU response
+    pub notify_timeout: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            adapter: None,
+            first: false,
+            ignore_case: false,
+            min_rssi: None,
+            address: None,
+            id: None,
+            dfu_adv_name: None,
+            no_buttonless: false,
+            mtu: None,
+            data_write_mode: DataWriteMode::NoAck,
+            scan_timeout: Duration::from_secs(30),
+            settle_delay: Duration::from_millis(500),
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_secs(1),
+            connect_attempts: 3,
+            connect_backoff: Duration::from_millis(250),
+            write_timeout: Duration::from_millis(500),
+            notify_timeout: Duration::from_millis(500),
+        }
     }
-    Err("unexpected end of stream".into())
 }
 
-async fn timeout<F: std::future::Future>(future: F) -> Result<F::Output, tokio::time::error::Elapsed> {
-    tokio::time::timeout(std::time::Duration::from_millis(500), future).await
+impl ConnectOptions {
+    /// Whether the advertised `name` matches `pattern`, the exact name or a prefix followed by `*`;
+    /// with [`ConnectOptions::ignore_case`] also in another case or padded
+    pub fn name_matches(&self, pattern: &str, name: &str) -> bool {
+        let (pattern, name) = if self.ignore_case {
+            // fixed-width advertising fields leave trailing spaces or NULs
+            let normalize = |s: &str| s.trim_matches(|c: char| c.is_whitespace() || c == '\0').to_lowercase();
+            (normalize(pattern), normalize(name))
+        } else {
+            (pattern.to_string(), name.to_string())
+        };
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        }
+    }
+}
+
+/// Name advertised by the bootloader when in DFU mode
+pub const BOOTLOADER_NAME: &str = "DfuTarg";
+
+/// Time the application gets to reset into the bootloader after the buttonless switch
+const RESET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the connection state is checked once the adapter stopped reporting disconnects
+const CONNECTION_POLL: Duration = Duration::from_millis(500);
+
+/// Control point notifications, forwarded from the peripheral's notification stream
+struct Notifications {
+    received: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Received while waiting for the response to a request
+    pending: VecDeque<Vec<u8>>,
 }
 
 pub struct DfuTransportBtleplug {
+    /// Name of the device connected to, the bootloader's after a buttonless switch
+    name: String,
+    /// Options the connection was made with
+    options: ConnectOptions,
+    central: Adapter,
     peripheral: Peripheral,
     control_point: Characteristic,
     data_point: Characteristic,
+    /// Subscribed once, so that no notification is missed between requests
+    notifications: tokio::sync::Mutex<Notifications>,
+    /// Task forwarding the control point notifications
+    forwarder: tokio::task::JoinHandle<()>,
+    /// Cancelled by the watcher task once the target disconnected
+    disconnected: CancellationToken,
+    /// Task watching for the target to disconnect
+    watcher: tokio::task::JoinHandle<()>,
+    data_writes: std::sync::Mutex<DataWrites>,
+    /// Set once [`DfuTransportBtleplug::close`] ran, so that dropping the transport does not clean up
+    /// again
+    closed: AtomicBool,
+}
+
+impl Drop for DfuTransportBtleplug {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        self.watcher.abort();
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        // best effort, the runtime may shut down before the cleanup ran
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let central = self.central.clone();
+            let peripheral = self.peripheral.clone();
+            let control_point = self.control_point.clone();
+            let within = self.options.write_timeout;
+            runtime.spawn(async move { cleanup(&central, &peripheral, &control_point, within).await });
+        }
+    }
+}
+
+/// Unsubscribe from the control point and disconnect if still connected, and stop any scan; errors
+/// are logged, as they must not hide the outcome of the update
+async fn cleanup(central: &Adapter, peripheral: &Peripheral, control_point: &Characteristic, within: Duration) {
+    let log = |what: &str, res: Result<Result<(), btleplug::Error>, tokio::time::error::Elapsed>| match res
+        .map_err(TransportError::from)
+        .and_then(|res| res.map_err(TransportError::from))
+    {
+        Ok(()) => debug!("{} done", what),
+        Err(e) => warn!("{} failed: {}", what, e),
+    };
+    // a target that reset on its own has nothing left to clean up
+    if peripheral.is_connected().await.unwrap_or(true) {
+        log(
+            "unsubscribing from the control point",
+            tokio::time::timeout(within, peripheral.unsubscribe(control_point)).await,
+        );
+        log(
+            "disconnecting",
+            tokio::time::timeout(within, peripheral.disconnect()).await,
+        );
+    }
+    // not scanning is no failure worth a warning
+    if let Err(e) = central.stop_scan().await {
+        debug!("stopping the scan failed: {}", e);
+    }
+}
+
+/// How data point writes are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataWriteMode {
+    /// Without response, the fastest
+    NoAck,
+    /// With response, for stacks that drop writes without response under load
+    Ack,
+    /// Without response until the CRC shows writes getting lost repeatedly within an object
+    Auto,
+}
+
+impl std::str::FromStr for DataWriteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "noack" => Ok(DataWriteMode::NoAck),
+            "ack" => Ok(DataWriteMode::Ack),
+            "auto" => Ok(DataWriteMode::Auto),
+            _ => Err(format!("unknown data write mode {}, expected ack, noack or auto", s)),
+        }
+    }
+}
+
+/// CRC mismatches within an object after which [`DataWriteMode::Auto`] switches to writes with
+/// response
+const LOST_WRITES_LIMIT: u32 = 2;
+
+/// Bookkeeping of [`DataWriteMode::Auto`], which compares the data written with the offsets of the
+/// CRC responses
+#[derive(Debug, Default)]
+struct DataWrites {
+    /// Writes with response from now on
+    acked: bool,
+    /// Offset of the last CRC response, unknown after creating an object
+    offset: Option<usize>,
+    /// Bytes written since
+    written: usize,
+    /// CRC responses since the last object executed that show writes missing
+    mismatches: u32,
+}
+
+impl DataWrites {
+    /// Track a data write of `len` bytes, sent as returned
+    fn writing(&mut self, len: usize) -> WriteType {
+        self.written += len;
+        if self.acked {
+            WriteType::WithResponse
+        } else {
+            WriteType::WithoutResponse
+        }
+    }
+    /// Track the control point `request` and its `response`
+    fn exchanged(&mut self, request: &[u8], response: &[u8]) {
+        match request.first() {
+            Some(&OBJECT_CREATE) => self.offset = None,
+            Some(&OBJECT_EXECUTE) => self.mismatches = 0,
+            Some(&CRC_GET) => {
+                let Some(offset) = response.get(3..7) else {
+                    return;
+                };
+                let offset = u32::from_le_bytes(offset.try_into().unwrap()) as usize;
+                if self.offset.is_some_and(|last| offset < last + self.written) {
+                    self.mismatches += 1;
+                    if !self.acked && self.mismatches >= LOST_WRITES_LIMIT {
+                        warn!("data writes keep getting lost, switching to writes with response");
+                        self.acked = true;
+                    }
+                }
+                self.offset = Some(offset);
+                self.written = 0;
+            }
+            _ => {}
+        }
+    }
 }
 
 #[async_trait]
-impl DfuTransport for &DfuTransportBtleplug {
-    async fn mtu(&self) -> usize {
-        // TODO fix once btleplug supports MTU lookup
-        244
+impl DfuTransport for DfuTransportBtleplug {
+    async fn mtu(&self) -> Option<usize> {
+        // btleplug 0.11 does not expose the negotiated MTU on any platform, only the caller can tell;
+        // without it, the protocol asks the target and falls back to the default ATT MTU of 23 bytes
+        self.options.mtu
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        // the protocol should already send shards that fit, splitting here keeps a mismatch from
+        // getting writes truncated
+        let payload = self.options.mtu.map_or(bytes.len(), |mtu| mtu.saturating_sub(3)).max(1);
+        if bytes.len() > payload {
+            debug!(
+                "splitting a {} byte data write into writes of {} bytes, the ATT payload",
+                bytes.len(),
+                payload
+            );
+        }
+        let write_type = match self.options.data_write_mode {
+            DataWriteMode::NoAck => WriteType::WithoutResponse,
+            DataWriteMode::Ack => WriteType::WithResponse,
+            DataWriteMode::Auto => self.data_writes.lock().unwrap().writing(bytes.len()),
+        };
+        let write = async {
+            for chunk in bytes.chunks(payload) {
+                self.peripheral.write(&self.data_point, chunk, write_type).await?;
+            }
+            Ok(())
+        };
+        self.linked(async {
+            match tokio::time::timeout(timeout, write).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(self.link_error(e).await),
+                Err(_) => Err(TransportError::WriteTimeout),
+            }
+        })
+        .await
     }
-    async fn write_data(&self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        self.write(&self.data_point, bytes, WriteType::WithoutResponse).await
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        let response = self
+            .linked(self.request(&self.control_point, bytes, WriteType::WithResponse, timeout))
+            .await?;
+        if self.options.data_write_mode == DataWriteMode::Auto {
+            self.data_writes.lock().unwrap().exchanged(bytes, &response);
+        }
+        Ok(response)
     }
-    async fn request_ctrl(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-        self.request(&self.control_point, bytes, WriteType::WithResponse).await
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        // only packet receipt notifications are unsolicited, anything else is a late response to a
+        // request that timed out
+        let is_receipt = |value: &[u8]| value.get(..2) == Some(&[RESPONSE, CRC_GET]);
+        let mut notifications = self.notifications.lock().await;
+        while let Some(value) = notifications.pending.pop_front() {
+            if is_receipt(&value) {
+                return Ok(value);
+            }
+            debug!("dropping unsolicited control point notification {:02x?}", value);
+        }
+        let next = async {
+            loop {
+                let value = notifications.received.recv().await.ok_or(TransportError::StreamEnded)?;
+                if is_receipt(&value) {
+                    return Ok(value);
+                }
+                debug!("dropping unsolicited control point notification {:02x?}", value);
+            }
+        };
+        self.linked(async { tokio::time::timeout(timeout, next).await? }).await
     }
 }
 
 impl DfuTransportBtleplug {
-    async fn write(&self, chr: &Characteristic, bytes: &[u8], write_type: WriteType) -> Result<(), Box<dyn Error>> {
-        let res = timeout(self.peripheral.write(chr, bytes, write_type)).await?;
-        Ok(res?)
-    }
     async fn request(
         &self,
         chr: &Characteristic,
         bytes: &[u8],
         write_type: WriteType,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut notifications = self.peripheral.notifications().await.unwrap();
-        timeout(self.peripheral.write(chr, bytes, write_type)).await??;
-        loop {
-            let ntf = timeout(notifications.next()).await?.unwrap();
-            if ntf.uuid == chr.uuid {
-                return Ok(ntf.value);
+        timeout: Duration,
+    ) -> Result<Vec<u8>, TransportError> {
+        let mut notifications = self.notifications.lock().await;
+        let Notifications { received, pending } = &mut *notifications;
+        // whatever arrived before the request was sent, e.g. a packet receipt notification in the same
+        // format as a CRC response, cannot be its response
+        while let Ok(value) = received.try_recv() {
+            pending.push_back(value);
+        }
+        // the write takes a connection event, the response may take seconds while the target erases flash
+        let write = self.peripheral.write(chr, bytes, write_type);
+        match tokio::time::timeout(self.options.write_timeout, write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(self.link_error(e).await),
+            Err(_) => return Err(TransportError::WriteTimeout),
+        }
+        let response = async {
+            loop {
+                let value = received.recv().await.ok_or(TransportError::StreamEnded)?;
+                // responses echo the request opcode, anything else is left for `receive_ctrl`
+                if value.first() == Some(&RESPONSE) && value.get(1) == bytes.first() {
+                    return Ok(value);
+                }
+                pending.push_back(value);
+            }
+        };
+        tokio::time::timeout(timeout, response).await?
+    }
+    /// Options the connection was made with, the address being the one connected to
+    pub fn options(&self) -> &ConnectOptions {
+        &self.options
+    }
+    /// Run `op` unless the target disconnects first, which fails it with
+    /// [`TransportError::Disconnected`] right away instead of once it times out
+    async fn linked<T>(
+        &self,
+        op: impl std::future::Future<Output = Result<T, TransportError>>,
+    ) -> Result<T, TransportError> {
+        tokio::select! {
+            biased;
+            _ = self.disconnected.cancelled() => Err(TransportError::Disconnected),
+            res = op => res,
+        }
+    }
+    /// Wait up to `within` until the target drops the connection, e.g. when resetting after
+    /// activating an image
+    pub async fn wait_disconnect(&self, within: Duration) -> Result<(), TransportError> {
+        tokio::time::timeout(within, self.disconnected.cancelled())
+            .await
+            .map_err(|_| TransportError::NoDisconnect)
+    }
+    /// [`TransportError::Disconnected`] if the failure of an operation with `e` is due to the link
+    /// having dropped
+    async fn link_error(&self, e: btleplug::Error) -> TransportError {
+        match self.peripheral.is_connected().await {
+            Ok(false) => TransportError::Disconnected,
+            _ if matches!(e, btleplug::Error::NotConnected) => TransportError::Disconnected,
+            _ => e.into(),
+        }
+    }
+    /// Scan for the device again and connect to it, e.g. after the link dropped, making up to
+    /// [`ConnectOptions::reconnect_attempts`] attempts; fails with the error of the last one
+    pub async fn reconnect(&mut self) -> Result<(), TransportError> {
+        // the old connection is most likely gone already
+        self.close().await;
+        let attempts = self.options.reconnect_attempts.max(1);
+        let mut backoff = self.options.reconnect_backoff;
+        for attempt in 1.. {
+            match Self::connect(&self.name, &self.options).await {
+                Ok(transport) => {
+                    // the link stays as lossy as it was
+                    let acked = self.data_writes.lock().unwrap().acked;
+                    *self = transport;
+                    self.data_writes.lock().unwrap().acked = acked;
+                    break;
+                }
+                Err(e) if attempt >= attempts => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "reconnect attempt {}/{} failed: {}, retrying in {:?}",
+                        attempt, attempts, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
             }
         }
+        Ok(())
+    }
+    /// Unsubscribe from the control point and drop the connection
+    pub async fn disconnect(&self) -> Result<(), TransportError> {
+        let within = self.options.write_timeout;
+        tokio::time::timeout(within, self.peripheral.unsubscribe(&self.control_point)).await??;
+        tokio::time::timeout(within, self.peripheral.disconnect()).await??;
+        Ok(())
+    }
+    /// Unsubscribe from the control point, disconnect and stop scanning, logging what fails; does
+    /// nothing once closed, dropping the transport without closing it cleans up in the background
+    pub async fn close(&self) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.watcher.abort();
+        cleanup(
+            &self.central,
+            &self.peripheral,
+            &self.control_point,
+            self.options.write_timeout,
+        )
+        .await
+    }
+    /// Connect to the device advertising `name` with the default [`ConnectOptions`]
+    pub async fn new(name: &str) -> Result<Self, TransportError> {
+        Self::connect(name, &ConnectOptions::default()).await
     }
-    pub async fn new(name: &str) -> Result<Self, Box<dyn Error>> {
-        let manager = btleplug::platform::Manager::new().await?;
-        let adapters = manager.adapters().await?;
-        let central = adapters.into_iter().next().unwrap();
+    /// Connect to the device advertising `name`, switching it to the bootloader first if it offers
+    /// buttonless DFU
+    pub async fn connect(name: &str, options: &ConnectOptions) -> Result<Self, TransportError> {
+        let adapters = select_adapters(options.adapter.as_deref()).await?;
+        info!(
+            "BLE timeouts: {:?} for writes, {:?} for notifications",
+            options.write_timeout, options.notify_timeout
+        );
 
-        let mut peripheral = find_peripheral_by_name(&central, name).await?;
-        peripheral.connect().await?;
-        peripheral.discover_services().await?;
+        let direct = match options.address {
+            Some(address) => match find_known(&adapters, address).await {
+                Some((central, peripheral)) => {
+                    info!("Connecting to {} directly ...", address);
+                    match connect_peripheral(&peripheral, options).await {
+                        Ok(characteristics) => Some((central, peripheral, characteristics)),
+                        Err(e) => {
+                            warn!("connecting to {} directly failed: {}, scanning for it", address, e);
+                            None
+                        }
+                    }
+                }
+                None => {
+                    info!("{} is not known to the adapters yet, scanning for it", address);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (central, mut peripheral, mut characteristics) = match direct {
+            Some(direct) => direct,
+            None => {
+                let (central, peripheral) = find_on_adapters(adapters, name, options).await?;
+                let characteristics = connect_peripheral(&peripheral, options).await?;
+                (central, peripheral, characteristics)
+            }
+        };
+        // reconnecting looks for this very device, not any matching the prefix
+        let mut name = match peripheral.properties().await? {
+            Some(properties) => properties.local_name.unwrap_or_else(|| name.to_string()),
+            None => name.to_string(),
+        };
 
-        // TODO find a better place for buttonless DFU
-        if let Ok(buttonless) = find_characteristic_by_uuid(&peripheral, BTTNLSS).await {
-            peripheral.subscribe(&buttonless).await?;
-            let mut notifications = peripheral.notifications().await.unwrap();
-            peripheral.write(&buttonless, &[0x01], WriteType::WithResponse).await?;
-            let res = timeout(notifications.next()).await?.unwrap();
-            assert_eq!(res.value, [0x20, 0x01, 0x01]);
+        match &characteristics {
+            DfuCharacteristics::Bootloader { .. } => info!("{} is in DFU mode already", name),
+            DfuCharacteristics::Buttonless { .. } => info!("{} runs an application offering buttonless DFU", name),
+        }
 
-            peripheral = find_peripheral_by_name(&central, "DfuTarg").await?;
-            peripheral.connect().await?;
-            peripheral.discover_services().await?;
+        if let DfuCharacteristics::Buttonless { buttonless, bonded } = characteristics {
+            enter_bootloader(&peripheral, &buttonless, bonded, options).await?;
+            // the application's advertisements must not be mistaken for the bootloader's
+            wait_disconnect(&central, &peripheral, RESET_TIMEOUT).await?;
+            tokio::time::sleep(options.settle_delay).await;
+            let id = peripheral.id();
+            peripheral = if bonded {
+                // the bootloader keeps the address and the bond, but may advertise without a name
+                find_peripheral(&central, &name, options, Choice::First, |found, _| *found == id).await?
+            } else if let Some(adv_name) = &options.dfu_adv_name {
+                find_peripheral(&central, adv_name, options, Choice::First, |_, properties| {
+                    properties.local_name.as_deref() == Some(adv_name.as_str())
+                })
+                .await?
+            } else {
+                // the bootloader advertises at the next address, or where the platform hides
+                // addresses (macOS) at least the DFU service
+                let address = peripheral.address();
+                let target = if address == BDAddr::default() {
+                    "the bootloader".to_string()
+                } else {
+                    format!("the bootloader at {}", bootloader_address(address))
+                };
+                find_peripheral(&central, &target, options, Choice::First, |found, properties| {
+                    if address == BDAddr::default() || properties.address == BDAddr::default() {
+                        *found != id
+                            && (advertises_dfu(properties) || properties.local_name.as_deref() == Some(BOOTLOADER_NAME))
+                    } else {
+                        properties.address == bootloader_address(address)
+                    }
+                })
+                .await?
+            };
+            if !bonded {
+                // reconnecting looks for the bootloader, whatever its name
+                name = match peripheral.properties().await? {
+                    Some(properties) => properties.local_name.unwrap_or_else(|| BOOTLOADER_NAME.to_string()),
+                    None => BOOTLOADER_NAME.to_string(),
+                };
+            }
+            characteristics = connect_peripheral(&peripheral, options).await?;
         }
 
-        let control_point = find_characteristic_by_uuid(&peripheral, CTRL_PT).await?;
-        let data_point = find_characteristic_by_uuid(&peripheral, DATA_PT).await?;
+        let DfuCharacteristics::Bootloader {
+            control_point,
+            data_point,
+        } = characteristics
+        else {
+            return Err(TransportError::CharacteristicNotFound {
+                uuid: CTRL_PT,
+                found: gatt_table(&peripheral),
+            });
+        };
+        // created before subscribing, so that not even the first notification or a disconnect right
+        // away is missed
+        let mut events = central.events().await?;
+        let mut stream = peripheral.notifications().await?;
         peripheral.subscribe(&control_point).await?;
+        let (forward, received) = tokio::sync::mpsc::unbounded_channel();
+        let uuid = control_point.uuid;
+        let forwarder = tokio::spawn(async move {
+            while let Some(ntf) = stream.next().await {
+                // other characteristics are no concern of the DFU
+                if ntf.uuid != uuid {
+                    trace!("dropping notification of {}: {:02x?}", ntf.uuid, ntf.value);
+                    continue;
+                }
+                if forward.send(ntf.value).is_err() {
+                    break;
+                }
+            }
+        });
+        let disconnected = CancellationToken::new();
+        let id = peripheral.id();
+        let dropped = disconnected.clone();
+        let connection = peripheral.clone();
+        let watcher = tokio::spawn(async move {
+            loop {
+                match events.next().await {
+                    Some(CentralEvent::DeviceDisconnected(ev_id)) if ev_id == id => break,
+                    Some(_) => {}
+                    None => {
+                        // the adapter no longer reports events, which says nothing about the target
+                        debug!("adapter event stream ended, polling the connection state instead");
+                        while connection.is_connected().await.unwrap_or(true) {
+                            tokio::time::sleep(CONNECTION_POLL).await;
+                        }
+                        break;
+                    }
+                }
+            }
+            debug!("target disconnected");
+            dropped.cancel();
+        });
+        if !peripheral.is_connected().await? {
+            disconnected.cancel();
+        }
+        // reconnecting looks for the bootloader, which may advertise at the next address or with a
+        // different identifier
+        let mut options = options.clone();
+        let address = Some(peripheral.address()).filter(|address| *address != BDAddr::default());
+        options.address = options.address.and(address);
+        options.id = options.id.and(Some(peripheral.id().to_string()));
         Ok(DfuTransportBtleplug {
+            name,
+            options,
+            central,
             peripheral,
             control_point,
             data_point,
+            notifications: tokio::sync::Mutex::new(Notifications {
+                received,
+                pending: VecDeque::new(),
+            }),
+            forwarder,
+            disconnected,
+            watcher,
+            data_writes: Default::default(),
+            closed: AtomicBool::new(false),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertising(name: &str, address: &str, rssi: Option<i16>) -> PeripheralProperties {
+        PeripheralProperties {
+            address: address.parse().unwrap(),
+            local_name: Some(name.to_string()),
+            rssi,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names() {
+        let exact = ConnectOptions::default();
+        assert!(exact.name_matches("DfuTarg", "DfuTarg"));
+        assert!(!exact.name_matches("DfuTarg", "DfuTarg2"));
+        assert!(!exact.name_matches("DfuTarg", "dfutarg"));
+        assert!(!exact.name_matches("DfuTarg", "DfuTarg\0"));
+        assert!(exact.name_matches("Dfu*", "DfuTarg"));
+        assert!(!exact.name_matches("Dfu*", "dfuTarg"));
+        assert!(exact.name_matches("*", "anything"));
+        let loose = ConnectOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        assert!(loose.name_matches("DfuTarg", "dfutarg \0\0"));
+        assert!(loose.name_matches(" dfu*", "DFUTARG"));
+        assert!(!loose.name_matches("DfuTarg", "DfuTarget"));
+    }
+
+    #[test]
+    fn ids() {
+        let bluez = "/org/bluez/hci0/dev_C0_98_E5_49_00_01";
+        assert!(id_matches("C0:98:E5:49:00:01", bluez));
+        assert!(id_matches("c0-98-e5-49-00-01", bluez));
+        assert!(id_matches("hci0/dev_C0_98_E5_49_00_01", bluez));
+        assert!(id_matches(bluez, bluez));
+        assert!(!id_matches("hci1/dev_C0_98_E5_49_00_01", bluez));
+        assert!(!id_matches("C0:98:E5:49:00:02", bluez));
+        let corebluetooth = "4A5B6C7D-0000-1111-2222-333344445555";
+        assert!(id_matches("4a5b6c7d-0000-1111-2222-333344445555", corebluetooth));
+        assert!(!id_matches("4a5b6c7d", corebluetooth));
+    }
+
+    #[test]
+    fn properties() {
+        let target = advertising("DfuTarg", "C0:98:E5:49:00:01", Some(-60));
+        let options = ConnectOptions::default();
+        assert!(properties_match("DfuTarg", &options, &target));
+        assert!(!properties_match("Other", &options, &target));
+        let unnamed = PeripheralProperties {
+            local_name: None,
+            ..target.clone()
+        };
+        assert!(!properties_match("DfuTarg", &options, &unnamed));
+
+        let near = ConnectOptions {
+            min_rssi: Some(-70),
+            ..Default::default()
+        };
+        assert!(properties_match("DfuTarg", &near, &target));
+        assert!(!properties_match(
+            "DfuTarg",
+            &near,
+            &advertising("DfuTarg", "C0:98:E5:49:00:01", Some(-80))
+        ));
+        assert!(!properties_match(
+            "DfuTarg",
+            &near,
+            &advertising("DfuTarg", "C0:98:E5:49:00:01", None)
+        ));
+
+        let at = ConnectOptions {
+            address: Some("C0:98:E5:49:00:01".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(properties_match("DfuTarg", &at, &target));
+        assert!(!properties_match(
+            "DfuTarg",
+            &at,
+            &advertising("DfuTarg", "C0:98:E5:49:00:02", Some(-60))
+        ));
+        // macOS reports no addresses
+        assert!(properties_match(
+            "DfuTarg",
+            &at,
+            &advertising("DfuTarg", "00:00:00:00:00:00", Some(-60))
+        ));
+    }
+
+    #[test]
+    fn choice() {
+        let options = ConnectOptions::default();
+        assert_eq!(Choice::of("DfuTarg", &options), Choice::First);
+        assert_eq!(Choice::of("Dfu*", &options), Choice::Unique);
+        let first = ConnectOptions {
+            first: true,
+            ..Default::default()
+        };
+        assert_eq!(Choice::of("Dfu*", &first), Choice::First);
+        let near = ConnectOptions {
+            min_rssi: Some(-70),
+            ..first
+        };
+        assert_eq!(Choice::of("Dfu*", &near), Choice::Strongest);
+        assert_eq!(Choice::of("DfuTarg", &near), Choice::Strongest);
+
+        assert_eq!(strongest([Some(-80), None, Some(-40), Some(-40)]), 2);
+        assert_eq!(strongest([None, Some(-90)]), 1);
+        assert_eq!(strongest([None, None]), 0);
+    }
+
+    /// CRC response for `offset` bytes
+    fn crc_response(offset: u32) -> Vec<u8> {
+        [&[RESPONSE, CRC_GET, 0x01][..], &offset.to_le_bytes(), &[0; 4]].concat()
+    }
+
+    #[test]
+    fn auto_data_writes() {
+        let mut writes = DataWrites::default();
+        let create =
+            |writes: &mut DataWrites| writes.exchanged(&[OBJECT_CREATE, 0x02], &[RESPONSE, OBJECT_CREATE, 0x01]);
+        let execute = |writes: &mut DataWrites| writes.exchanged(&[OBJECT_EXECUTE], &[RESPONSE, OBJECT_EXECUTE, 0x01]);
+        create(&mut writes);
+        // after creating an object the first CRC only tells where the data starts
+        assert_eq!(writes.writing(100), WriteType::WithoutResponse);
+        writes.exchanged(&[CRC_GET], &crc_response(50));
+        // one write got lost
+        writes.writing(100);
+        writes.exchanged(&[CRC_GET], &crc_response(100));
+        // a truncated response tells nothing
+        writes.exchanged(&[CRC_GET], &[RESPONSE, CRC_GET, 0x01]);
+        writes.writing(100);
+        writes.exchanged(&[CRC_GET], &crc_response(200));
+        assert_eq!(writes.writing(0), WriteType::WithoutResponse);
+
+        // executing an object forgets the mismatches of the previous one
+        execute(&mut writes);
+        create(&mut writes);
+        writes.exchanged(&[CRC_GET], &crc_response(200));
+        writes.writing(100);
+        writes.exchanged(&[CRC_GET], &crc_response(250));
+        assert_eq!(writes.writing(100), WriteType::WithoutResponse);
+        writes.exchanged(&[CRC_GET], &crc_response(300));
+        assert_eq!(writes.writing(100), WriteType::WithResponse);
+        // for the rest of the transfer
+        execute(&mut writes);
+        assert_eq!(writes.writing(100), WriteType::WithResponse);
+    }
+
+    #[test]
+    fn bootloader_addresses() {
+        let address = |address: &str| address.parse::<BDAddr>().unwrap();
+        assert_eq!(
+            bootloader_address(address("C0:98:E5:49:00:01")),
+            address("C0:98:E5:49:00:02")
+        );
+        // the increment does not carry into the next byte
+        assert_eq!(
+            bootloader_address(address("C0:98:E5:49:00:FF")),
+            address("C0:98:E5:49:00:00")
+        );
+    }
+
+    #[test]
+    fn adv_names() {
+        assert!(check_adv_name("DfuTarg").is_ok());
+        assert!(check_adv_name(&"x".repeat(ADV_NAME_MAX)).is_ok());
+        assert!(check_adv_name(&"x".repeat(ADV_NAME_MAX + 1)).is_err());
+        assert!(check_adv_name("").is_err());
+    }
+}
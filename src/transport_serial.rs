@@ -0,0 +1,275 @@
+//! DFU over a serial port, e.g. a UART wired to the target, as Nordic's serial bootloader transport
+//!
+//! Requests and responses are the same as over BLE, SLIP encoded; data is sent with the object write
+//! request (0x08) instead of through a separate characteristic.
+
+use crate::transport::{ControlPoint, DfuTransport, TransportError, RESPONSE};
+
+use async_trait::async_trait;
+use log::{debug, info};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+
+/// SLIP frame delimiter and escapes
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Opcode of requesting the MTU, which over serial is the longest frame the target decodes
+const MTU_GET: u8 = 0x07;
+/// Opcode of writing data to the current object
+const OBJECT_WRITE: u8 = 0x08;
+/// Opcode of pinging the target
+const PING: u8 = 0x09;
+/// Result code of a successful request
+const SUCCESS: u8 = 0x01;
+
+/// How to open the port
+#[derive(Debug, Clone)]
+pub struct SerialOptions {
+    /// Baud rate, 115200 by default as in Nordic's bootloader
+    pub baud: u32,
+    /// Timeout of the ping and MTU request when opening the port
+    pub timeout: Duration,
+    /// How long [`DfuTransportSerial::reconnect`] keeps trying to reach the bootloader, e.g. while
+    /// it resets
+    pub reconnect_timeout: Duration,
+}
+
+impl Default for SerialOptions {
+    fn default() -> Self {
+        SerialOptions {
+            baud: 115200,
+            timeout: Duration::from_millis(500),
+            reconnect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// SLIP encoding of `bytes` as one frame
+fn slip_encode(bytes: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(bytes.len() + 2);
+    for &byte in bytes {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            _ => frame.push(byte),
+        }
+    }
+    frame.push(END);
+    frame
+}
+
+/// Incremental SLIP decoder
+#[derive(Default)]
+struct SlipDecoder {
+    frame: Vec<u8>,
+    escaped: bool,
+}
+
+impl SlipDecoder {
+    /// Feed a received byte, returns the frame it completes
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match (self.escaped, byte) {
+            (false, END) if self.frame.is_empty() => {}
+            (false, END) => return Some(std::mem::take(&mut self.frame)),
+            (false, ESC) => self.escaped = true,
+            (false, _) => self.frame.push(byte),
+            (true, _) => {
+                self.escaped = false;
+                self.frame.push(match byte {
+                    ESC_END => END,
+                    ESC_ESC => ESC,
+                    // a protocol violation, kept as is for the response check to reject
+                    _ => byte,
+                });
+            }
+        }
+        None
+    }
+}
+
+pub struct DfuTransportSerial {
+    /// Path of the port, e.g. `/dev/ttyACM0`
+    path: String,
+    /// Options the port was opened with
+    options: SerialOptions,
+    writer: tokio::sync::Mutex<WriteHalf<SerialStream>>,
+    /// Frames decoded by the reader task
+    ctrl: ControlPoint,
+    /// Longest data write, what fits into the target's frame when every byte needs escaping
+    payload: usize,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for DfuTransportSerial {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[async_trait]
+impl DfuTransport for DfuTransportSerial {
+    async fn mtu(&self) -> Option<usize> {
+        // the protocol sends ATT MTU - 3 bytes per write
+        Some(self.payload + 3)
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        let mut request = Vec::with_capacity(bytes.len() + 1);
+        request.push(OBJECT_WRITE);
+        request.extend_from_slice(bytes);
+        tokio::time::timeout(timeout, self.send(&request))
+            .await
+            .map_err(|_| TransportError::WriteTimeout)?
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        // the write only fills the driver's buffer, the deadline is practically the response's
+        tokio::time::timeout(timeout, self.request(bytes)).await?
+    }
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.ctrl.receive(timeout).await
+    }
+}
+
+impl DfuTransportSerial {
+    /// Open the serial port at `path` and check that the bootloader answers on it
+    pub async fn open(path: &str, options: &SerialOptions) -> Result<Self, TransportError> {
+        info!("Opening {} at {} baud ...", path, options.baud);
+        let port = tokio_serial::new(path, options.baud)
+            .open_native_async()
+            .map_err(|e| TransportError::Backend(Box::new(e)))?;
+        port.clear(tokio_serial::ClearBuffer::All)
+            .map_err(|e| TransportError::Backend(Box::new(e)))?;
+        let (mut port_reader, writer) = tokio::io::split(port);
+        let (frames, received) = tokio::sync::mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            let mut decoder = SlipDecoder::default();
+            let mut buf = [0; 256];
+            loop {
+                match port_reader.read(&mut buf).await {
+                    // the port hung up, e.g. a USB device that reset or was unplugged; the closed
+                    // channel reports the disconnect
+                    Ok(0) => break,
+                    Ok(n) => {
+                        for &byte in &buf[..n] {
+                            if let Some(frame) = decoder.push(byte) {
+                                if frames.send(Ok(frame)).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        let _ = frames.send(Err(e));
+                        break;
+                    }
+                }
+            }
+            debug!("serial port closed");
+        });
+        let mut transport = DfuTransportSerial {
+            path: path.to_string(),
+            options: options.clone(),
+            writer: tokio::sync::Mutex::new(writer),
+            ctrl: ControlPoint::new(received),
+            payload: 0,
+            reader,
+        };
+        let timeout = options.timeout;
+        let pong = tokio::time::timeout(timeout, transport.request(&[PING, 0x01])).await??;
+        if pong.get(2) != Some(&SUCCESS) {
+            return Err(TransportError::Backend(
+                format!("unexpected ping response {:02x?}", pong).into(),
+            ));
+        }
+        let response = tokio::time::timeout(timeout, transport.request(&[MTU_GET])).await??;
+        let mtu = match response[..] {
+            [RESPONSE, MTU_GET, SUCCESS, lo, hi, ..] => u16::from_le_bytes([lo, hi]) as usize,
+            _ => {
+                return Err(TransportError::Backend(
+                    format!("unexpected MTU response {:02x?}", response).into(),
+                ))
+            }
+        };
+        // the MTU has to hold the write opcode and a data byte, escaped
+        if mtu < 3 {
+            return Err(TransportError::Backend(format!("unexpected MTU {}", mtu).into()));
+        }
+        // the object write opcode takes a byte, and every byte may need escaping
+        transport.payload = ((mtu - 1) / 2).saturating_sub(1).max(1);
+        info!(
+            "Bootloader on {} has an MTU of {} bytes, writing {} bytes of data at a time",
+            path, mtu, transport.payload
+        );
+        Ok(transport)
+    }
+    /// Options the port was opened with
+    pub fn options(&self) -> &SerialOptions {
+        &self.options
+    }
+    /// Open the port again, e.g. after the target reset or was unplugged, until the bootloader
+    /// answers or [`SerialOptions::reconnect_timeout`] passed
+    pub async fn reconnect(&mut self) -> Result<(), TransportError> {
+        let deadline = tokio::time::Instant::now() + self.options.reconnect_timeout;
+        loop {
+            match Self::open(&self.path, &self.options).await {
+                Ok(transport) => {
+                    *self = transport;
+                    return Ok(());
+                }
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(e) => {
+                    debug!("opening {} failed: {}, retrying", self.path, e);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+    async fn send(&self, bytes: &[u8]) -> Result<(), TransportError> {
+        let frame = slip_encode(bytes);
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        Ok(())
+    }
+    async fn request(&self, bytes: &[u8]) -> Result<Vec<u8>, TransportError> {
+        self.ctrl.request(bytes, self.send(bytes)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = SlipDecoder::default();
+        bytes.iter().filter_map(|&byte| decoder.push(byte)).collect()
+    }
+
+    #[test]
+    fn slip_escaping() {
+        assert_eq!(slip_encode(&[0x01, END, 0x02]), [0x01, ESC, ESC_END, 0x02, END]);
+        assert_eq!(slip_encode(&[ESC, ESC_END]), [ESC, ESC_ESC, ESC_END, END]);
+        assert_eq!(slip_encode(&[]), [END]);
+        let frame = [RESPONSE, END, ESC, ESC_END, ESC_ESC, 0x00];
+        assert_eq!(decode(&slip_encode(&frame)), [frame]);
+    }
+
+    #[test]
+    fn slip_frames() {
+        // a leading END, as senders use to flush line noise, and empty frames are skipped
+        let bytes = [END, 0x01, 0x02, END, END, 0x03, END];
+        assert_eq!(decode(&bytes), [vec![0x01, 0x02], vec![0x03]]);
+        // a frame completes only with its END
+        assert!(decode(&[0x01, ESC, ESC_END]).is_empty());
+    }
+
+    #[test]
+    fn slip_invalid_escape() {
+        // the escaped byte is kept as is for the response check to reject, even an END
+        assert_eq!(decode(&[0x01, ESC, 0x02, 0x03, END]), [vec![0x01, 0x02, 0x03]]);
+        assert_eq!(decode(&[ESC, END, END]), [vec![END]]);
+    }
+}
@@ -0,0 +1,201 @@
+//! DFU over a TCP connection, to exercise the whole stack against a simulated target without
+//! Bluetooth, e.g. `testutil::serve` in tests
+//!
+//! Each frame is the length of the rest of the frame as 16 bit little endian, the channel
+//! ([`CONTROL_POINT`] or [`DATA_POINT`]) and the payload, i.e. what would be written to or notified
+//! on the characteristic.
+
+use crate::transport::{ControlPoint, DfuTransport, TransportError};
+
+use async_trait::async_trait;
+use log::debug;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+
+/// Channel of control point requests, responses and notifications
+pub const CONTROL_POINT: u8 = 0x01;
+/// Channel of data writes
+pub const DATA_POINT: u8 = 0x02;
+
+/// Read a frame, `None` once the peer closed the connection
+pub async fn read_frame(reader: &mut (impl tokio::io::AsyncRead + Unpin)) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut frame = vec![0; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut frame).await?;
+    if frame.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame without channel",
+        ));
+    }
+    let payload = frame.split_off(1);
+    Ok(Some((frame[0], payload)))
+}
+
+/// Write a frame of `payload` on `channel`
+pub async fn write_frame(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    channel: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let len = u16::try_from(payload.len() + 1)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too long"))?;
+    let mut frame = Vec::with_capacity(payload.len() + 3);
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.push(channel);
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+pub struct DfuTransportTcp {
+    /// Address connected to, e.g. `127.0.0.1:7777`
+    addr: String,
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    /// Control point frames received by the reader task
+    ctrl: ControlPoint,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for DfuTransportTcp {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[async_trait]
+impl DfuTransport for DfuTransportTcp {
+    async fn mtu(&self) -> Option<usize> {
+        // the target reports it like a bootloader does
+        None
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        tokio::time::timeout(timeout, self.send(DATA_POINT, bytes))
+            .await
+            .map_err(|_| TransportError::WriteTimeout)?
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        tokio::time::timeout(timeout, self.request(bytes)).await?
+    }
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.ctrl.receive(timeout).await
+    }
+}
+
+impl DfuTransportTcp {
+    /// Connect to the simulated target at `addr`
+    pub async fn connect(addr: &str) -> Result<Self, TransportError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, writer) = stream.into_split();
+        let (frames, received) = tokio::sync::mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            loop {
+                match read_frame(&mut reader).await {
+                    Ok(Some((CONTROL_POINT, payload))) => {
+                        if frames.send(Ok(payload)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Some((channel, payload))) => {
+                        debug!("dropping frame on channel {}: {:02x?}", channel, payload)
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = frames.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(DfuTransportTcp {
+            addr: addr.to_string(),
+            writer: tokio::sync::Mutex::new(writer),
+            ctrl: ControlPoint::new(received),
+            reader,
+        })
+    }
+    /// Connect to the target again, e.g. after it closed the connection
+    pub async fn reconnect(&mut self) -> Result<(), TransportError> {
+        *self = Self::connect(&self.addr).await?;
+        Ok(())
+    }
+    async fn send(&self, channel: u8, bytes: &[u8]) -> Result<(), TransportError> {
+        let mut writer = self.writer.lock().await;
+        write_frame(&mut *writer, channel, bytes).await?;
+        Ok(())
+    }
+    async fn request(&self, bytes: &[u8]) -> Result<Vec<u8>, TransportError> {
+        self.ctrl.request(bytes, self.send(CONTROL_POINT, bytes)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, DfuOptions, OpCode};
+    use crate::testutil::{self, MockDfuTransport};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Unsigned init packet of an application with fw_version 1
+    const INIT_PKT: &[u8] = &[0x0a, 0x06, 0x08, 0x01, 0x12, 0x02, 0x08, 0x01];
+
+    /// Simulated bootloader on a local port, and its address
+    async fn serve(target: &Arc<MockDfuTransport>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(testutil::serve(listener, target.clone()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn round_trip_with_simulated_target() {
+        let fw: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+        for prn in [0, 4] {
+            let target = Arc::new(MockDfuTransport::new());
+            let transport = DfuTransportTcp::connect(&serve(&target).await).await.unwrap();
+            let options = DfuOptions {
+                prn,
+                ..Default::default()
+            };
+            let summary = protocol::dfu_run_with_options(&transport, INIT_PKT, &fw[..], fw.len(), &options)
+                .await
+                .unwrap();
+            assert_eq!(target.command(), INIT_PKT);
+            assert_eq!(target.data(), fw, "{}", prn);
+            assert_eq!(target.executed(), fw.len());
+            assert_eq!((summary.objects, summary.mtu, summary.ctrl_retries), (3, 247, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_and_reconnect() {
+        // the server does not answer the dropped request at all
+        let target = Arc::new(MockDfuTransport::new().drop_responses(OpCode::CrcGet, 1));
+        let mut transport = DfuTransportTcp::connect(&serve(&target).await).await.unwrap();
+        let mut options = DfuOptions::default();
+        options.timeouts.crc = Duration::from_millis(200);
+        let fw = vec![0x5a; 1000];
+        let summary = protocol::dfu_run_with_options(&transport, INIT_PKT, &fw[..], fw.len(), &options)
+            .await
+            .unwrap();
+        assert_eq!(summary.ctrl_retries, 1);
+        assert_eq!(target.data(), fw);
+
+        // the server takes the next connection to the same target, which still holds the init packet
+        transport.reconnect().await.unwrap();
+        options.force_full = true;
+        let fw = vec![0xa5; 2000];
+        protocol::dfu_run_with_options(&transport, INIT_PKT, &fw[..], fw.len(), &options)
+            .await
+            .unwrap();
+        assert_eq!(target.data(), fw);
+    }
+}
@@ -0,0 +1,184 @@
+//! DFU over USB CDC-ACM, as Nordic's open bootloader on the nRF52840 dongle (PCA10059) speaks it
+//!
+//! The bootloader's CDC interface is a serial port running the serial DFU transport, so this finds
+//! the port of the device by its USB vendor and product ID and hands it to
+//! [`DfuTransportSerial`]. The port is looked up through sysfs, so this is only available on Linux.
+
+use crate::transport::{DfuTransport, TransportError};
+use crate::transport_serial::{DfuTransportSerial, SerialOptions};
+
+use async_trait::async_trait;
+use log::debug;
+use std::time::Duration;
+
+/// Nordic Semiconductor's USB vendor ID
+pub const NORDIC_VID: u16 = 0x1915;
+/// USB product ID of the open bootloader
+pub const OPEN_BOOTLOADER_PID: u16 = 0x521F;
+
+/// USB vendor and product ID, parsed from and printed as `VID:PID` in hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl Default for UsbId {
+    fn default() -> Self {
+        UsbId {
+            vid: NORDIC_VID,
+            pid: OPEN_BOOTLOADER_PID,
+        }
+    }
+}
+
+impl std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
+    }
+}
+
+impl std::str::FromStr for UsbId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = |s: &str| {
+            let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+            u16::from_str_radix(digits, 16)
+        };
+        match s.split_once(':') {
+            Some((vid, pid)) => match (hex(vid), hex(pid)) {
+                (Ok(vid), Ok(pid)) => Ok(UsbId { vid, pid }),
+                _ => Err(format!("invalid USB ID {:?}, expected hex VID:PID", s)),
+            },
+            None => Err(format!("invalid USB ID {:?}, expected VID:PID, e.g. 1915:521f", s)),
+        }
+    }
+}
+
+/// Serial port of the USB device `id`, e.g. `/dev/ttyACM0`
+pub fn find_port(id: UsbId) -> Result<String, TransportError> {
+    // the tty's device is the CDC interface, the USB device holding the IDs is one of its parents
+    fn usb_id(tty: &std::path::Path) -> Option<UsbId> {
+        let device = std::fs::canonicalize(tty.join("device")).ok()?;
+        let read = |dir: &std::path::Path, file| {
+            let text = std::fs::read_to_string(dir.join(file)).ok()?;
+            u16::from_str_radix(text.trim(), 16).ok()
+        };
+        device.ancestors().find_map(|dir| {
+            Some(UsbId {
+                vid: read(dir, "idVendor")?,
+                pid: read(dir, "idProduct")?,
+            })
+        })
+    }
+    let mut seen = Vec::new();
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir("/sys/class/tty")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(tty_id) = usb_id(&entry.path()) {
+            debug!("USB serial port {} ({})", name, tty_id);
+            if tty_id == id {
+                found.push(format!("/dev/{}", name));
+            }
+            seen.push(format!("{} ({})", name, tty_id));
+        }
+    }
+    match found.len() {
+        0 => Err(TransportError::DeviceNotFound {
+            name: format!("USB {}", id),
+            seen,
+        }),
+        1 => Ok(found.remove(0)),
+        _ => Err(TransportError::AmbiguousDevice {
+            name: format!("USB {}", id),
+            candidates: found,
+        }),
+    }
+}
+
+pub struct DfuTransportUsb {
+    /// USB device to (re)open
+    id: UsbId,
+    serial: DfuTransportSerial,
+}
+
+#[async_trait]
+impl DfuTransport for DfuTransportUsb {
+    async fn mtu(&self) -> Option<usize> {
+        self.serial.mtu().await
+    }
+    async fn write_data(&self, bytes: &[u8], timeout: Duration) -> Result<(), TransportError> {
+        self.serial.write_data(bytes, timeout).await
+    }
+    async fn request_ctrl(&self, bytes: &[u8], timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.serial.request_ctrl(bytes, timeout).await
+    }
+    async fn receive_ctrl(&self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.serial.receive_ctrl(timeout).await
+    }
+}
+
+impl DfuTransportUsb {
+    /// Find the USB device `id` and open its serial port; the baud rate in `options` does not
+    /// matter for CDC-ACM
+    pub async fn open(id: UsbId, options: &SerialOptions) -> Result<Self, TransportError> {
+        let port = find_port(id)?;
+        Ok(DfuTransportUsb {
+            id,
+            serial: DfuTransportSerial::open(&port, options).await?,
+        })
+    }
+    /// Find the device again and reopen its port, as it enumerates anew after a reset and may get a
+    /// different port, until [`SerialOptions::reconnect_timeout`] passed
+    pub async fn reconnect(&mut self) -> Result<(), TransportError> {
+        let options = self.serial.options().clone();
+        let deadline = tokio::time::Instant::now() + options.reconnect_timeout;
+        loop {
+            match Self::open(self.id, &options).await {
+                Ok(transport) => {
+                    *self = transport;
+                    return Ok(());
+                }
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(e) => {
+                    debug!("opening USB device {} failed: {}, retrying", self.id, e);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_ids() {
+        let dongle = UsbId::default();
+        assert_eq!(dongle.to_string(), "1915:521f");
+        for id in ["1915:521f", "1915:521F", "0x1915:0x521f", "0X1915:521F"] {
+            assert_eq!(id.parse::<UsbId>(), Ok(dongle), "{}", id);
+        }
+        assert_eq!("1:2".parse::<UsbId>(), Ok(UsbId { vid: 1, pid: 2 }));
+        assert_eq!(UsbId { vid: 1, pid: 0xab }.to_string(), "0001:00ab");
+    }
+
+    #[test]
+    fn invalid_usb_ids() {
+        for id in [
+            "1915",
+            "1915-521f",
+            "",
+            "1915:",
+            ":521f",
+            "1915:521g",
+            "0x0x1915:521f",
+            "11915:521f",
+        ] {
+            assert!(id.parse::<UsbId>().is_err(), "{}", id);
+        }
+        assert!("1915".parse::<UsbId>().unwrap_err().contains("expected VID:PID"));
+    }
+}
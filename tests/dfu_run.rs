@@ -0,0 +1,335 @@
+//! `dfu_run` against the in-memory bootloader of the `testutil` feature
+
+use nrfdfu_ble::protocol::{self, DfuOptions, DfuSummary, OpCode, ProtocolError, ResponseCode, RetryPolicy};
+use nrfdfu_ble::testutil::MockDfuTransport;
+use nrfdfu_ble::DfuError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Unsigned init packet of an application with fw_version 1
+const INIT_PKT: &[u8] = &[0x0a, 0x06, 0x08, 0x01, 0x12, 0x02, 0x08, 0x01];
+
+fn fw(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+/// Default options with quick retries
+fn options() -> DfuOptions {
+    DfuOptions {
+        retry: RetryPolicy {
+            delay: Duration::from_millis(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+async fn run(target: &MockDfuTransport, fw_pkt: &[u8], options: &DfuOptions) -> Result<DfuSummary, DfuError> {
+    protocol::dfu_run_with_options(target, INIT_PKT, fw_pkt, fw_pkt.len(), options).await
+}
+
+/// Data objects created, without the command object
+fn data_objects(target: &MockDfuTransport) -> usize {
+    let create = u8::from(OpCode::ObjectCreate);
+    target.requests().iter().filter(|r| r.starts_with(&[create, 2])).count()
+}
+
+#[tokio::test]
+async fn small_image() {
+    let target = MockDfuTransport::new();
+    let fw_pkt = fw(100);
+    let summary = protocol::dfu_run(&target, INIT_PKT, &fw_pkt[..], fw_pkt.len())
+        .await
+        .unwrap();
+    assert_eq!(target.command(), INIT_PKT);
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(target.executed(), fw_pkt.len());
+    assert_eq!((summary.bytes, summary.objects, summary.shard_size), (100, 1, 244));
+    assert_eq!(
+        (summary.ctrl_retries, summary.crc_retries, summary.execute_retries),
+        (0, 0, 0)
+    );
+}
+
+#[tokio::test]
+async fn multi_object_image() {
+    for max_size in [4096, 1024] {
+        let target = MockDfuTransport::new().max_size(max_size);
+        let fw_pkt = fw(10000);
+        let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+        let objects = fw_pkt.len().div_ceil(max_size);
+        assert_eq!(target.data(), fw_pkt, "{}", max_size);
+        assert_eq!(target.executed(), fw_pkt.len());
+        assert_eq!((summary.objects, data_objects(&target)), (objects, objects));
+    }
+}
+
+#[tokio::test]
+async fn dropped_response_is_retried() {
+    let target = MockDfuTransport::new().drop_responses(OpCode::CrcGet, 2);
+    let fw_pkt = fw(5000);
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.ctrl_retries, 2);
+    assert_eq!(summary.crc_retries, 0);
+}
+
+#[tokio::test]
+async fn missing_responses_give_up() {
+    // the default policy makes 3 attempts
+    let target = MockDfuTransport::new().drop_responses(OpCode::CrcGet, 3);
+    let err = run(&target, &fw(5000), &options()).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DfuError::Protocol(ProtocolError::NoResponse {
+                opcode: OpCode::CrcGet,
+                attempts: 3
+            })
+        ),
+        "{}",
+        err
+    );
+    assert!(err.is_link_error());
+}
+
+#[tokio::test]
+async fn corrupted_byte_recreates_object() {
+    // write 1 is the init packet, so this corrupts the fifth shard of the first data object
+    let target = MockDfuTransport::new().corrupt_write(6);
+    let fw_pkt = fw(10000);
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.crc_retries, 1);
+    // the corrupted object was created again
+    assert_eq!(data_objects(&target), 3 + 1);
+}
+
+#[tokio::test]
+async fn corrupted_byte_fails_without_attempts_left() {
+    let target = MockDfuTransport::new().corrupt_write(6);
+    let options = DfuOptions {
+        object_attempts: 1,
+        ..options()
+    };
+    let err = run(&target, &fw(10000), &options).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DfuError::Protocol(ProtocolError::TransferFailed { offset: 0, attempts: 1 })
+        ),
+        "{}",
+        err
+    );
+    // nothing corrupted was executed
+    assert_eq!(target.executed(), 0);
+}
+
+#[tokio::test]
+async fn lost_shard_is_resent() {
+    let target = MockDfuTransport::new().drop_write(6);
+    let fw_pkt = fw(10000);
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.crc_retries, 1);
+    // the target still agreed with the data before the lost shard, so the object was kept
+    assert_eq!(data_objects(&target), 3);
+}
+
+#[tokio::test]
+async fn resume_after_disconnect() {
+    // the link drops in the middle of the second data object
+    let target = MockDfuTransport::new().disconnect_at(20);
+    let fw_pkt = fw(10000);
+    let err = run(&target, &fw_pkt, &options()).await.unwrap_err();
+    assert!(err.is_link_error(), "{}", err);
+    let transferred = target.data().len();
+    assert!(transferred > 4096 && target.executed() == 4096, "{}", transferred);
+
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.bytes, fw_pkt.len() - transferred);
+    assert_eq!(summary.objects, 1);
+}
+
+#[tokio::test]
+async fn confirmed_offset_of_failed_run() {
+    let target = MockDfuTransport::new().disconnect_at(20);
+    let fw_pkt = fw(10000);
+    let confirmed = Arc::new(AtomicUsize::new(0));
+    let options = DfuOptions {
+        confirmed: Some(confirmed.clone()),
+        ..options()
+    };
+    run(&target, &fw_pkt, &options).await.unwrap_err();
+    // the shards written after the last CRC check are not counted
+    let confirmed = confirmed.load(Ordering::Relaxed);
+    assert!(confirmed > 4096 && confirmed <= target.data().len(), "{}", confirmed);
+
+    run(&target, &fw_pkt, &options).await.unwrap();
+    assert_eq!(options.confirmed.unwrap().load(Ordering::Relaxed), fw_pkt.len());
+}
+
+#[tokio::test]
+async fn receipt_notifications() {
+    let target = MockDfuTransport::new();
+    let fw_pkt = fw(10000);
+    let options = DfuOptions { prn: 4, ..options() };
+    run(&target, &fw_pkt, &options).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    // the CRC is only requested for the init packet and at the end of data objects no receipt
+    // covers, the last one ends with a receipt after 8 shards
+    let crc_get = u8::from(OpCode::CrcGet);
+    let crc_requests = target.requests().iter().filter(|r| r[0] == crc_get).count();
+    assert_eq!(crc_requests, 1 + 2);
+}
+
+#[tokio::test]
+async fn large_init_packet() {
+    // the init packet with an unknown field as padding, like long sd_req lists, exceeds the ATT
+    // payload that the mock target truncates writes to
+    let mut init_pkt = INIT_PKT.to_vec();
+    init_pkt.extend_from_slice(&[0x7a, 0x90, 0x03]);
+    init_pkt.resize(INIT_PKT.len() + 3 + 400, 0);
+    let fw_pkt = fw(1000);
+    for att_mtu in [247, 23] {
+        let target = MockDfuTransport::new().att_mtu(att_mtu);
+        protocol::dfu_run_with_options(&target, &init_pkt, &fw_pkt[..], fw_pkt.len(), &options())
+            .await
+            .unwrap();
+        assert_eq!(target.command(), init_pkt, "{}", att_mtu);
+        assert_eq!(target.data(), fw_pkt);
+    }
+}
+
+/// Execute requests, of the command and the data objects
+fn executes(target: &MockDfuTransport) -> usize {
+    let execute = u8::from(OpCode::ObjectExecute);
+    target.requests().iter().filter(|r| r[0] == execute).count()
+}
+
+#[tokio::test(start_paused = true)]
+async fn busy_execute_is_retried_with_backoff() {
+    // the execute of the command object fails twice while the bootloader is busy
+    let target = MockDfuTransport::new().reject(OpCode::ObjectExecute, ResponseCode::OperationFailed, 2);
+    let fw_pkt = fw(5000);
+    let start = tokio::time::Instant::now();
+    let summary = run(&target, &fw_pkt, &DfuOptions::default()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.execute_retries, 2);
+    assert_eq!(executes(&target), 3 + 2);
+    // the default delay of 100 ms doubles for the second retry
+    assert!(
+        start.elapsed() >= Duration::from_millis(100 + 200),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_execute_is_not_retried() {
+    // erasing flash for the first data object takes a while
+    let target = MockDfuTransport::new().delay_response(OpCode::ObjectExecute, 2, Duration::from_secs(3));
+    let fw_pkt = fw(5000);
+    let start = tokio::time::Instant::now();
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.executed(), fw_pkt.len());
+    assert_eq!((summary.ctrl_retries, summary.execute_retries), (0, 0));
+    assert_eq!(executes(&target), 3);
+    assert!(start.elapsed() >= Duration::from_secs(3));
+}
+
+#[tokio::test(start_paused = true)]
+async fn timed_out_execute_took_effect() {
+    let target = MockDfuTransport::new().delay_response(OpCode::ObjectExecute, 2, Duration::from_secs(3));
+    let fw_pkt = fw(5000);
+    let mut options = options();
+    options.timeouts.execute = Duration::from_secs(1);
+    let summary = run(&target, &fw_pkt, &options).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    // the execute sent again is not permitted for the object that was executed already
+    assert_eq!(summary.execute_retries, 1);
+    assert_eq!(executes(&target), 3 + 1);
+}
+
+#[tokio::test]
+async fn busy_execute_is_checked_before_retrying() {
+    let target = MockDfuTransport::new().reject(OpCode::ObjectExecute, ResponseCode::OperationFailed, 1);
+    run(&target, &fw(100), &options()).await.unwrap();
+    // the failed execute of the command object is followed by a select of it
+    let requests = target.requests();
+    let execute = u8::from(OpCode::ObjectExecute);
+    let failed = requests.iter().position(|r| r[0] == execute).unwrap();
+    assert_eq!(requests[failed + 1], [u8::from(OpCode::ObjectSelect), 1]);
+    assert_eq!(requests[failed + 2], [execute]);
+}
+
+#[tokio::test]
+async fn busy_execute_gives_up() {
+    // the default policy retries twice
+    let target = MockDfuTransport::new().reject(OpCode::ObjectExecute, ResponseCode::OperationFailed, 3);
+    let err = run(&target, &fw(100), &options()).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DfuError::Protocol(ProtocolError::Rejected {
+                opcode: OpCode::ObjectExecute,
+                code: ResponseCode::OperationFailed
+            })
+        ),
+        "{}",
+        err
+    );
+    assert_eq!(executes(&target), 3);
+}
+
+#[tokio::test]
+async fn invalid_execute_is_not_retried() {
+    let target = MockDfuTransport::new().reject(OpCode::ObjectExecute, ResponseCode::InvalidObject, 1);
+    let err = run(&target, &fw(100), &options()).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DfuError::Protocol(ProtocolError::Rejected {
+                code: ResponseCode::InvalidObject,
+                ..
+            })
+        ),
+        "{}",
+        err
+    );
+    assert_eq!(executes(&target), 1);
+}
+
+#[tokio::test]
+async fn reduced_bootloader() {
+    // bootloaders built with NRF_DFU_PROTOCOL_REDUCED, which never support the hardware and
+    // firmware version requests of the mock either
+    let target = MockDfuTransport::new()
+        .unsupported(OpCode::ProtocolVersion)
+        .unsupported(OpCode::Ping)
+        .unsupported(OpCode::MtuGet)
+        .unsupported(OpCode::Abort);
+    let fw_pkt = fw(5000);
+    let summary = run(&target, &fw_pkt, &options()).await.unwrap();
+    assert_eq!(target.data(), fw_pkt);
+    assert_eq!(summary.protocol_version, None);
+    assert!(summary.hardware.is_none() && summary.firmware.is_empty());
+    // the shards fall back to the payload of the default ATT MTU
+    assert_eq!((summary.mtu, summary.shard_size), (23, 20));
+
+    // a failure still tells the target to abort, which it does not support either
+    let target = MockDfuTransport::new().unsupported(OpCode::Abort).corrupt_write(6);
+    let options = DfuOptions {
+        object_attempts: 1,
+        ..options()
+    };
+    let err = run(&target, &fw_pkt, &options).await.unwrap_err();
+    assert!(
+        matches!(err, DfuError::Protocol(ProtocolError::TransferFailed { .. })),
+        "{}",
+        err
+    );
+    assert_eq!(target.requests().last().unwrap(), &[u8::from(OpCode::Abort)]);
+}